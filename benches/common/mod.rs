@@ -0,0 +1,25 @@
+use std::time::{Duration, Instant};
+use rlisp::lisparse::{init_env, Env, Eval};
+
+/// Runs `f` `samples` times after one warm-up run and prints the fastest and
+/// the median time.
+pub fn bench(name: &str, samples: usize, mut f: impl FnMut()) {
+    f();
+    let mut times: Vec<Duration> = (0..samples).map(|_| {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }).collect();
+    times.sort();
+    println!("{:<28} min {:>10.3?}  median {:>10.3?}", name, times[0], times[times.len() / 2]);
+}
+
+/// A fresh environment with `defs` evaluated in it.
+#[allow(dead_code)]
+pub fn env_with(defs: &[&str]) -> Box<Env> {
+    let mut env = Box::new(init_env());
+    for def in defs {
+        Eval(def, &mut env);
+    }
+    env
+}
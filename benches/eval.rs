@@ -0,0 +1,41 @@
+use std::hint::black_box;
+use rlisp::lisparse::Eval;
+
+mod common;
+use common::{bench, env_with};
+
+fn main() {
+    let mut env = env_with(&["(define fibo (lambda (n) (if (< n 2) n (+ (fibo (- n 1)) (fibo (- n 2))))))"]);
+    bench("fibo 20", 10, || { black_box(Eval(black_box("(fibo 20)"), &mut env)); });
+
+    let mut env = env_with(&[
+        "(define range (lambda (n acc) (if (= n 0) acc (range (- n 1) (cons n acc)))))",
+        "(define big (range 1000 nil))",
+        // looks up a 1000 element global list on every iteration
+        "(define walk (lambda (i n) (if (= i 0) n (walk (- i 1) (+ n (car (cdr big)))))))",
+        "(define drop (lambda (l i) (if (= i 0) l (drop (cdr l) (- i 1)))))",
+        "(define grow (lambda (l i) (if (= i 0) l (grow (append l (list i)) (- i 1)))))",
+    ]);
+    bench("lookup 1000 element list", 10, || { black_box(Eval(black_box("(walk 1000 0)"), &mut env)); });
+    bench("cons 1000", 10, || { black_box(Eval(black_box("(range 1000 nil)"), &mut env)); });
+    bench("cdr down 1000", 10, || { black_box(Eval(black_box("(drop big 999)"), &mut env)); });
+    bench("append 300", 10, || { black_box(Eval(black_box("(grow (list 0) 300)"), &mut env)); });
+
+    let mut env = env_with(&[
+        "(define make-adder (lambda (n) (lambda (x) (+ x n))))",
+        "(define compose (lambda (f g) (lambda (x) (f (g x)))))",
+        "(define chain (lambda (i f) (if (= i 0) f (chain (- i 1) (compose (make-adder i) f)))))",
+        "(define make-counter (lambda () (define n 0) (lambda () (set! n (+ n 1)) n)))",
+        "(define tick (lambda (c i) (c) (if (= i 0) (c) (tick c (- i 1)))))",
+    ]);
+    bench("compose 200 closures", 10, || { black_box(Eval(black_box("((chain 200 (make-adder 0)) 0)"), &mut env)); });
+    bench("counter 10000", 10, || { black_box(Eval(black_box("(tick (make-counter) 10000)"), &mut env)); });
+
+    let mut env = env_with(&[
+        "(define range (lambda (n acc) (if (= n 0) acc (range (- n 1) (cons n acc)))))",
+        "(define xs (range 1000 nil))",
+        "(define ys (range 1000 nil))",
+    ]);
+    bench("map 1000", 10, || { black_box(Eval(black_box("(map + xs ys)"), &mut env)); });
+    bench("apply 1000", 10, || { black_box(Eval(black_box("(apply max 0 xs)"), &mut env)); });
+}
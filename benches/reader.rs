@@ -0,0 +1,23 @@
+use std::hint::black_box;
+use rlisp::lisparse::{cvt_to_nested_expression, split_cmd_to_vec};
+
+mod common;
+use common::bench;
+
+/// `n` definitions mixing numbers, strings, symbols, quotes and nesting.
+fn source(n: usize) -> String {
+    (0..n).map(|i| format!(
+        "(define f{i} (lambda (x y) (if (< x {i}) (cons 'a{i} (list x y \"str {i}\" 3.25)) (f{i} (- x 1) '(a (b c) \"d\")))))\n"
+    )).collect()
+}
+
+fn main() {
+    for n in [100, 1000] {
+        let src = source(n);
+        bench(&format!("tokenize {} forms", n), 10, || { black_box(split_cmd_to_vec(black_box(&src))); });
+        let tokens = split_cmd_to_vec(&src);
+        bench(&format!("parse {} forms", n), 10, || { let _ = black_box(cvt_to_nested_expression(black_box(&tokens), &mut 0, 0)); });
+    }
+    let deep = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+    bench("read 1000 deep", 10, || { let _ = black_box(cvt_to_nested_expression(&split_cmd_to_vec(black_box(&deep)), &mut 0, 0)); });
+}
@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fmt::Formatter;
+use std::rc::Rc;
+use crate::lisparse::{error, Atomic, Env, LispError, LispResult, LispType, Proc};
+use crate::symbol::Sym;
+
+/*
+Both engines keep a stack of the calls in progress in the `Env`: what is
+called and where the call is written, if it was read from source text. A
+procedure defined with define goes by that name, an anonymous one by where
+its lambda is. Every call pushes and pops an entry, so they are kept cheap:
+a name rather than the procedure, and no thread-local to go through.
+
+A call that fails leaves its entries behind while the failure unwinds, so a
+handler called for it sees them with (backtrace), under its own calls. They
+are dropped by whatever carries on: a call that returns drops everything
+above it, and a top-level form that fails keeps them for the error report:
+  The argument of 'car' should be a list 5
+    in car, called at lib.lisp:2:21
+    in second, called at <input>:1:1
+ */
+
+/// Where a form starts in its source; lines and columns count from 1.
+#[derive(Debug, PartialEq)]
+pub struct Span {
+    pub source: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.column)
+    }
+}
+
+/// What a call is of, as it is shown rather than the procedure itself.
+#[derive(Debug, Clone)]
+pub enum Callee {
+    /// a builtin, or "continuation"
+    Builtin(&'static str),
+    Named(Sym),
+    /// an anonymous procedure, by where its lambda is
+    Lambda(Option<Rc<Span>>),
+}
+
+impl Callee {
+    /// `f`, which is a procedure.
+    pub(crate) fn of(f: &LispType) -> Callee {
+        match f {
+            LispType::Atom(Atomic::Fun(f)) => Callee::Builtin(f.name),
+            LispType::Atom(Atomic::Proc(uf)) => Callee::proc(uf),
+            _ => Callee::Builtin("continuation"),
+        }
+    }
+
+    pub(crate) fn proc(uf: &Proc) -> Callee {
+        match uf.lambda.name {
+            Some(name) => Callee::Named(name),
+            None => Callee::Lambda(uf.lambda.span.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub callee: Callee,
+    pub at: Option<Rc<Span>>,
+}
+
+impl Call {
+    pub fn name(&self) -> Sym {
+        match &self.callee {
+            // builtins named %form are what the macro form expands to
+            Callee::Builtin(name) => Sym::intern(name.strip_prefix('%').unwrap_or(name)),
+            Callee::Named(name) => *name,
+            Callee::Lambda(_) => Sym::intern("lambda"),
+        }
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.callee {
+            Callee::Lambda(Some(span)) => write!(f, "in the lambda at {}", span)?,
+            Callee::Lambda(None) => write!(f, "in a lambda")?,
+            _ => write!(f, "in {}", self.name())?,
+        }
+        match &self.at {
+            Some(span) => write!(f, ", called at {}", span),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The calls in progress, innermost last.
+#[derive(Debug, Clone, Default)]
+pub struct Calls(Vec<Call>);
+
+impl Calls {
+    pub(crate) fn depth(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Records a call of `callee`; returns the depth to go back to once it returns.
+    pub(crate) fn enter(&mut self, callee: Callee, at: Option<Rc<Span>>) -> usize {
+        self.0.push(Call { callee, at });
+        self.0.len() - 1
+    }
+
+    /// Drops the calls from `depth` on.
+    pub(crate) fn leave(&mut self, depth: usize) {
+        self.0.truncate(depth);
+    }
+
+    /// The calls from `depth` on, innermost last.
+    pub(crate) fn from(&self, depth: usize) -> &[Call] {
+        &self.0[depth.min(self.0.len())..]
+    }
+
+    /// Replaces the calls from `depth` on with `saved`.
+    pub(crate) fn restore(&mut self, depth: usize, saved: &[Call]) {
+        self.0.truncate(depth);
+        self.0.extend_from_slice(saved);
+    }
+
+    /// Called when a top-level form that started at `depth` failed: keeps
+    /// the calls it left for `report`.
+    pub(crate) fn failed(&mut self, depth: usize) {
+        let left = self.0.split_off(depth.min(self.0.len()));
+        LAST.with(|last| *last.borrow_mut() = left);
+    }
+}
+
+thread_local! {
+    /// what the last top-level form that failed left, for `report`
+    static LAST: RefCell<Vec<Call>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Forgets the calls kept by the last failure.
+pub(crate) fn forget() {
+    LAST.with(|last| last.borrow_mut().clear());
+}
+
+/// `err` followed by the calls that were in progress when the last
+/// top-level form failed, innermost first, one per line.
+pub fn report(err: &LispError) -> String {
+    let mut out = err.to_string();
+    for call in LAST.with(|last| std::mem::take(&mut *last.borrow_mut())).iter().rev() {
+        out.push_str(&format!("\n  {}", call));
+    }
+    out
+}
+
+/*
+(backtrace) returns the calls in progress, innermost first, as lists
+(name source line column), or (name) for calls not read from source. In a
+handler, they include the calls the exception was raised in.
+ */
+pub fn backtrace(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    if !args.is_empty() {
+        return error(String::from("Usage: (backtrace)"));
+    }
+    // leaving out the call of backtrace itself
+    let calls = env.calls.from(0).split_last().map(|(_, rest)| rest).unwrap_or(&[]);
+    Ok(LispType::List(calls.iter().rev().map(|call| {
+        let mut entry = vec![LispType::Atom(Atomic::Symbol(call.name()))];
+        if let Some(span) = &call.at {
+            entry.push(LispType::Atom(Atomic::Str(span.source.clone())));
+            entry.push(LispType::Atom(Atomic::Number(span.line as i32)));
+            entry.push(LispType::Atom(Atomic::Number(span.column as i32)));
+        }
+        LispType::List(entry.into())
+    }).collect()))
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use crate::lisparse::*;
+    use super::report;
+
+    const LIB: &str = "(define second\n  (lambda (x) (car (cdr x))))\n(define deeper (lambda (x) (+ 1 (second x))))";
+
+    fn env_for(engine: Engine) -> Box<Env> {
+        let mut env = Box::new(init_env());
+        env.set_engine(engine);
+        load_source(LIB, "lib.lisp", &mut env).unwrap();
+        env
+    }
+
+    #[test]
+    fn test_spans() {
+        let forms = read_source("(a\n  (b \"é\" (c)))\n'(d)", "f.lisp").unwrap();
+        let span = |form: &LispType| match form {
+            LispType::List(list) => list.span().unwrap().to_string(),
+            _ => unreachable!(),
+        };
+        assert_eq!(span(&forms[0]), "f.lisp:1:1");
+        let inner = match &forms[0] {
+            LispType::List(list) => list[1].clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(span(&inner), "f.lisp:2:3");
+        assert_eq!(span(&forms[1]), "f.lisp:3:1");
+    }
+
+    #[test]
+    fn test_report() {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let mut env = env_for(engine);
+            let err = eval_source("(deeper 3)", &mut env).unwrap_err();
+            assert_eq!(report(&err), "The argument of 'cdr' should be a list 3\n  in cdr, called at lib.lisp:2:20\n  in second, called at lib.lisp:3:33\n  in deeper, called at <input>:1:1");
+            // the trace is reported once, and nothing is left behind
+            assert_eq!(report(&err), "The argument of 'cdr' should be a list 3");
+            assert_eq!(env.calls.depth(), 0);
+            let err = eval_source("(map (lambda (x)\n (second x)) '(1))", &mut env).unwrap_err();
+            assert_eq!(report(&err), "The argument of 'cdr' should be a list 1\n  in cdr, called at lib.lisp:2:20\n  in second, called at <input>:2:2\n  in map, called at <input>:1:1");
+            let err = eval_source("(deeper 1 2)", &mut env).unwrap_err();
+            assert_eq!(report(&err), "Unmatched arguments with parameters of deeper: expected 1, got 2\n  in deeper, called at <input>:1:1");
+        }
+    }
+
+    #[test]
+    fn test_backtrace_in_handlers() {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let mut env = env_for(engine);
+            assert_eq!(Eval("(backtrace)", &mut env).to_string(), "()");
+            Eval("(define where (lambda () (backtrace)))", &mut env);
+            assert_eq!(Eval("(list (where))", &mut env).to_string(), "(((where \"<input>\" 1 7)))");
+            // a guard sees where the exception was raised, the calls of its body aren't left behind
+            assert_eq!(Eval("(guard (e (t (cdr (backtrace)))) (deeper 3))", &mut env).to_string(),
+                       "((cdr \"lib.lisp\" 2 20) (second \"lib.lisp\" 3 33) (deeper \"<input>\" 1 34) (guard \"<input>\" 1 1))");
+            assert_eq!(env.calls.depth(), 0);
+            Eval("(define seen nil)", &mut env);
+            Eval("(with-exception-handler (lambda (e) (set! seen (cdr (backtrace))) 0) (lambda () (+ 1 (raise-continuable 'c))))", &mut env);
+            assert_eq!(Eval("seen", &mut env).to_string(), "((raise-continuable \"<input>\" 1 86) (lambda) (with-exception-handler \"<input>\" 1 1))");
+        }
+        // a continuation resumed in the VM brings its calls back
+        let mut env = env_for(Engine::Vm);
+        Eval("(define trace nil)", &mut env);
+        Eval("(define k nil)", &mut env);
+        Eval("(define g (lambda (x) (set! trace (backtrace)) x))", &mut env);
+        Eval("(define f (lambda () (+ 1 (g (call/cc (lambda (c) (set! k c) 1))))))", &mut env);
+        Eval("(f)", &mut env);
+        assert_eq!(Eval("trace", &mut env).to_string(), "((g \"<input>\" 1 27) (f \"<input>\" 1 1))");
+        assert_eq!(Eval("(k 10)", &mut env), LispType::Atom(Atomic::Number(11)));
+        assert_eq!(Eval("trace", &mut env).to_string(), "((g \"<input>\" 1 27) (f \"<input>\" 1 1) (continuation \"<input>\" 1 1))");
+        assert_eq!(env.calls.depth(), 0);
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: rlisp [options] [script [args...]]
+       rlisp fmt [--check] [--width N] [file...]
+
+Options:
+  -e EXPR          evaluate EXPR and print its value, unless it's nil,
+                   as definitions and output return
+  -f FILE          run FILE as a script
+  -i               start the REPL after the script or expressions
+  -q, --quiet      don't print the banner
+  --no-color       don't colour output
+  --history FILE   keep the REPL history in FILE
+  -h, --help       print this message
+  --               pass the remaining arguments to the script
+
+The script and the arguments after it are available as (command-line).
+When the REPL is going to start, the init file ($RLISP_INIT or ~/.rlisprc)
+is loaded first, so -e and -f with -i see what it defines.
+RLISP_ENGINE=vm runs code on the bytecode virtual machine instead of the
+tree-walking evaluator.
+
+fmt rewrites the files in the canonical style, or formats stdin to stdout.
+With --check it changes nothing, lists the files that aren't formatted and
+exits with 1 if there are any.";
+
+/// What to run, in command-line order.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Eval(String),
+    Load(PathBuf),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub actions: Vec<Action>,
+    /// the script followed by the arguments passed to it
+    pub command_line: Vec<String>,
+    pub interactive: bool,
+    pub quiet: bool,
+    pub color: bool,
+    pub history: Option<PathBuf>,
+    pub help: bool,
+    /// `rlisp fmt` instead of running anything
+    pub fmt: Option<FmtOptions>,
+}
+
+impl Options {
+    /// Whether the REPL starts once the actions have run: with -i, or when
+    /// there is nothing else to do and stdin isn't piped.
+    pub fn starts_repl(&self, stdin_is_terminal: bool) -> bool {
+        self.interactive || (self.actions.is_empty() && stdin_is_terminal)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FmtOptions {
+    pub check: bool,
+    pub width: usize,
+    pub files: Vec<PathBuf>,
+}
+
+fn parse_fmt(args: &mut dyn Iterator<Item = String>) -> Result<FmtOptions, String> {
+    let mut opts = FmtOptions { check: false, width: 80, files: Vec::new() };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => opts.check = true,
+            "--width" => {
+                let width = value(args, "--width")?;
+                opts.width = width.parse().ok().filter(|width| *width > 0)
+                    .ok_or_else(|| format!("{} is not a width", width))?;
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => return Err(format!("Unknown option {}", flag)),
+            _ => opts.files.push(PathBuf::from(arg)),
+        }
+    }
+    Ok(opts)
+}
+
+fn value(args: &mut dyn Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs an argument", flag))
+}
+
+/// Parses the arguments after the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut opts = Options { color: true, ..Options::default() };
+    let mut script: Option<String> = None;
+    let mut rest = Vec::new();
+    let mut args = args.into_iter().peekable();
+    if args.peek().is_some_and(|arg| arg == "fmt") {
+        args.next();
+        opts.fmt = Some(parse_fmt(&mut args)?);
+        return Ok(opts);
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" => opts.actions.push(Action::Eval(value(&mut args, "-e")?)),
+            "-f" => {
+                let file = value(&mut args, "-f")?;
+                opts.actions.push(Action::Load(PathBuf::from(&file)));
+                script.get_or_insert(file);
+            }
+            "-i" => opts.interactive = true,
+            "-q" | "--quiet" => opts.quiet = true,
+            "--no-color" => opts.color = false,
+            "--history" => opts.history = Some(PathBuf::from(value(&mut args, "--history")?)),
+            "-h" | "--help" => opts.help = true,
+            "--" => {
+                rest.extend(args.by_ref());
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => return Err(format!("Unknown option {}", flag)),
+            _ if script.is_none() => {
+                // options end at the script, everything after it is its own
+                opts.actions.push(Action::Load(PathBuf::from(&arg)));
+                script = Some(arg);
+                rest.extend(args.by_ref());
+            }
+            _ => rest.push(arg),
+        }
+    }
+    opts.command_line = std::iter::once(script.unwrap_or_else(|| String::from("rlisp"))).chain(rest).collect();
+    Ok(opts)
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Options, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse() {
+        let opts = parse_str("").unwrap();
+        assert!(opts.actions.is_empty() && opts.color && !opts.quiet);
+        assert_eq!(opts.command_line, vec!["rlisp"]);
+
+        let opts = parse_str("-q --no-color -e 3 -i").unwrap();
+        assert_eq!(opts.actions, vec![Action::Eval(String::from("3"))]);
+        assert!(opts.quiet && !opts.color && opts.interactive);
+
+        let opts = parse_str("-e 1 script.lisp -e 2 --no-color").unwrap();
+        assert_eq!(opts.actions, vec![Action::Eval(String::from("1")), Action::Load(PathBuf::from("script.lisp"))]);
+        assert_eq!(opts.command_line, vec!["script.lisp", "-e", "2", "--no-color"]);
+        assert!(opts.color);
+
+        let opts = parse_str("-f a.lisp -i -- x -y").unwrap();
+        assert_eq!(opts.actions, vec![Action::Load(PathBuf::from("a.lisp"))]);
+        assert_eq!(opts.command_line, vec!["a.lisp", "x", "-y"]);
+        assert!(opts.interactive);
+        rlisp::lisparse::set_command_line(opts.command_line);
+        let mut env = Box::new(rlisp::lisparse::init_env());
+        assert_eq!(rlisp::lisparse::Eval("(command-line)", &mut env).to_string(), "(\"a.lisp\" \"x\" \"-y\")");
+
+        assert_eq!(parse_str("--history h.txt").unwrap().history, Some(PathBuf::from("h.txt")));
+        let fmt = parse_str("fmt --check --width 60 a.lisp b.lisp").unwrap().fmt.unwrap();
+        assert!(fmt.check);
+        assert_eq!(fmt.width, 60);
+        assert_eq!(fmt.files, vec![PathBuf::from("a.lisp"), PathBuf::from("b.lisp")]);
+        assert_eq!(parse_str("fmt").unwrap().fmt, Some(FmtOptions { check: false, width: 80, files: vec![] }));
+        assert!(parse_str("fmt --width 0").is_err());
+        assert!(parse_str("-e").is_err());
+        assert!(parse_str("--bogus").is_err());
+    }
+
+    #[test]
+    fn test_starts_repl() {
+        assert!(parse_str("").unwrap().starts_repl(true));
+        assert!(!parse_str("").unwrap().starts_repl(false));
+        assert!(!parse_str("-e 1").unwrap().starts_repl(true));
+        assert!(parse_str("-e 1 -i").unwrap().starts_repl(false));
+        assert!(!parse_str("script.lisp -i").unwrap().starts_repl(true));
+    }
+}
@@ -0,0 +1,290 @@
+use std::cell::OnceCell;
+use std::rc::Rc;
+use crate::backtrace::Span;
+use crate::lisparse::{Atomic, LispError, LispType};
+use crate::symbol::Sym;
+use crate::syntax::SpecialForm;
+use crate::vm::Chunk;
+
+/// Expanded code after analysis. Variables are resolved once here: locals
+/// become a (depth, index) address into the chain of run-time frames and
+/// everything else is looked up in the global `Env` by name.
+#[derive(Debug)]
+pub enum Expr {
+    Const(LispType),
+    Local(usize, usize),
+    Global(Sym),
+    SetLocal(usize, usize, Box<Expr>),
+    SetGlobal(Sym, Box<Expr>),
+    DefineLocal(usize, Box<Expr>),
+    DefineGlobal(Sym, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    Lambda(Rc<Lambda>),
+    /// a call, and where it is written
+    Call(Box<Expr>, Vec<Expr>, Option<Rc<Span>>),
+}
+
+#[derive(Debug)]
+pub struct Lambda {
+    pub name: Option<Sym>,
+    pub params: Vec<Sym>,
+    /// number of slots of the frame, i.e. the parameters plus internal defines
+    pub frame_size: usize,
+    pub body: Vec<Expr>,
+    /// where the lambda is written
+    pub span: Option<Rc<Span>>,
+    /// the body compiled for the VM, filled in on the first call there
+    pub bytecode: OnceCell<Rc<Chunk>>,
+}
+
+/// Compile-time mirror of the frames that will exist at run time, innermost last.
+struct Scope {
+    frames: Vec<Vec<Sym>>,
+}
+
+impl Scope {
+    fn lookup(&self, name: Sym) -> Option<(usize, usize)> {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if let Some(idx) = frame.iter().rposition(|n| *n == name) {
+                return Some((depth, idx));
+            }
+        }
+        None
+    }
+}
+
+fn syntax_error(msg: String) -> LispError {
+    LispError::Error(format!("Syntax Error: {}", msg))
+}
+
+fn symbol_name(expr: &LispType) -> Option<Sym> {
+    match expr {
+        LispType::Atom(Atomic::Symbol(s)) => Some(*s),
+        _ => None,
+    }
+}
+
+/// The sym of (global sym).
+fn global_name(expr: &LispType) -> Option<Sym> {
+    match expr {
+        LispType::List(list) if list.len() == 2 && matches!(list[0], LispType::Atom(Atomic::Special(SpecialForm::Global))) => {
+            symbol_name(&list[1])
+        }
+        _ => None,
+    }
+}
+
+fn define_name(expr: &LispType) -> Option<Sym> {
+    match expr {
+        LispType::List(list) if list.len() > 1 && matches!(list[0], LispType::Atom(Atomic::Special(SpecialForm::Define))) => {
+            symbol_name(&list[1])
+        }
+        _ => None,
+    }
+}
+
+/// Compiles an expanded top-level form.
+pub fn compile(expr: &LispType) -> Result<Expr, LispError> {
+    analyze(expr, &mut Scope { frames: Vec::new() }, None)
+}
+
+fn analyze(expr: &LispType, scope: &mut Scope, name: Option<Sym>) -> Result<Expr, LispError> {
+    let list = match expr {
+        LispType::Atom(Atomic::Symbol(sym)) => {
+            return Ok(match scope.lookup(*sym) {
+                Some((depth, idx)) => Expr::Local(depth, idx),
+                None => Expr::Global(*sym),
+            });
+        }
+        LispType::List(list) if !list.is_empty() => list,
+        LispType::List(_) => return Ok(Expr::Const(LispType::Atom(Atomic::nil))),
+        LispType::Atom(_) => return Ok(Expr::Const(expr.clone())),
+    };
+    let form = match &list[0] {
+        LispType::Atom(Atomic::Special(form)) => *form,
+        head => {
+            let f = analyze(head, scope, None)?;
+            let args = list[1..].iter().map(|arg| analyze(arg, scope, None)).collect::<Result<_, _>>()?;
+            return Ok(Expr::Call(Box::new(f), args, list.span()));
+        }
+    };
+    match form {
+        SpecialForm::Quote => match list.len() {
+            2 => Ok(Expr::Const(list[1].clone())),
+            _ => Err(syntax_error(String::from("Usage: (quote expr)"))),
+        },
+        SpecialForm::If => {
+            if list.len() != 3 && list.len() != 4 {
+                return Err(syntax_error(String::from("Usage: (if cond conseq alt)")));
+            }
+            let alt = match list.get(3) {
+                Some(alt) => analyze(alt, scope, None)?,
+                None => Expr::Const(LispType::Atom(Atomic::nil)),
+            };
+            Ok(Expr::If(Box::new(analyze(&list[1], scope, None)?), Box::new(analyze(&list[2], scope, None)?), Box::new(alt)))
+        }
+        SpecialForm::Define => {
+            let sym = match (list.get(1).and_then(symbol_name), list.len()) {
+                (Some(sym), 2) | (Some(sym), 3) => sym,
+                _ => return Err(syntax_error(String::from("Usage: (define sym expr)"))),
+            };
+            if scope.frames.is_empty() {
+                let value = analyze_value(list.get(2), scope, Some(sym))?;
+                return Ok(Expr::DefineGlobal(sym, Box::new(value)));
+            }
+            let frame = scope.frames.last_mut().unwrap();
+            let idx = match frame.iter().rposition(|n| *n == sym) {
+                Some(idx) => idx,
+                None => {
+                    frame.push(sym);
+                    frame.len() - 1
+                }
+            };
+            let value = analyze_value(list.get(2), scope, Some(sym))?;
+            Ok(Expr::DefineLocal(idx, Box::new(value)))
+        }
+        SpecialForm::Set => {
+            if let (Some(sym), 3) = (list.get(1).and_then(global_name), list.len()) {
+                return Ok(Expr::SetGlobal(sym, Box::new(analyze(&list[2], scope, None)?)));
+            }
+            let sym = match (list.get(1).and_then(symbol_name), list.len()) {
+                (Some(sym), 3) => sym,
+                _ => return Err(syntax_error(String::from("Usage: (set! sym expr)"))),
+            };
+            let value = Box::new(analyze(&list[2], scope, None)?);
+            Ok(match scope.lookup(sym) {
+                Some((depth, idx)) => Expr::SetLocal(depth, idx, value),
+                None => Expr::SetGlobal(sym, value),
+            })
+        }
+        SpecialForm::Lambda => {
+            let params = match list.get(1) {
+                Some(LispType::List(params)) if list.len() > 2 => &params[..],
+                Some(LispType::Atom(Atomic::nil)) if list.len() > 2 => &[][..],
+                _ => return Err(syntax_error(String::from("Usage: (lambda (symbol...) expr...)"))),
+            };
+            let mut frame = Vec::new();
+            for param in params {
+                match symbol_name(param) {
+                    Some(sym) => frame.push(sym),
+                    None => return Err(syntax_error(format!("Invalid parameter {}", param))),
+                }
+            }
+            let params = frame.clone();
+            // internal defines get their slot up front so that they can refer to each other
+            for body in &list[2..] {
+                if let Some(sym) = define_name(body) {
+                    if !frame.contains(&sym) {
+                        frame.push(sym);
+                    }
+                }
+            }
+            scope.frames.push(frame);
+            let body = list[2..].iter().map(|e| analyze(e, scope, None)).collect::<Result<Vec<_>, _>>();
+            let frame = scope.frames.pop().unwrap();
+            Ok(Expr::Lambda(Rc::new(Lambda { name, params, frame_size: frame.len(), body: body?, span: list.span(), bytecode: OnceCell::new() })))
+        }
+        SpecialForm::DefineSyntax => Ok(Expr::Const(LispType::Atom(Atomic::nil))),
+        SpecialForm::Global => match global_name(expr) {
+            Some(sym) => Ok(Expr::Global(sym)),
+            None => Err(syntax_error(String::from("Usage: (global sym)"))),
+        },
+    }
+}
+
+fn analyze_value(value: Option<&LispType>, scope: &mut Scope, name: Option<Sym>) -> Result<Expr, LispError> {
+    match value {
+        Some(value) => analyze(value, scope, name),
+        None => Ok(Expr::Const(LispType::Atom(Atomic::nil))),
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::read_all;
+    use crate::syntax::SyntaxEnv;
+
+    fn compiled(src: &str) -> Result<Expr, LispError> {
+        let expanded = SyntaxEnv::default().expand(&read_all(src).unwrap()[0]).map_err(LispError::Error)?;
+        compile(&expanded)
+    }
+
+    fn lambda(expr: &Expr) -> &Lambda {
+        match expr {
+            Expr::Lambda(lambda) => lambda,
+            other => panic!("not a lambda: {:?}", other),
+        }
+    }
+
+    /// The arguments of a call, or the value of a define or set!.
+    fn operands(expr: &Expr) -> Vec<&Expr> {
+        match expr {
+            Expr::Call(f, args, _) => std::iter::once(&**f).chain(args).collect(),
+            Expr::SetLocal(_, _, value) | Expr::SetGlobal(_, value) | Expr::DefineLocal(_, value) | Expr::DefineGlobal(_, value) => vec![value],
+            other => panic!("no operands: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lexical_addresses() {
+        let outer = compiled("(lambda (a b) (lambda (c) (list a b c)))").unwrap();
+        let outer = lambda(&outer);
+        assert_eq!(outer.params, vec![Sym::intern("a"), Sym::intern("b")]);
+        let inner = lambda(&outer.body[0]);
+        assert_eq!(inner.frame_size, 1);
+        assert!(matches!(operands(&inner.body[0])[..], [Expr::Global(_), Expr::Local(1, 0), Expr::Local(1, 1), Expr::Local(0, 0)]));
+        // the innermost binding of a name wins
+        let shadowed = compiled("(lambda (x) (lambda (x) x))").unwrap();
+        assert!(matches!(lambda(&lambda(&shadowed).body[0]).body[..], [Expr::Local(0, 0)]));
+    }
+
+    #[test]
+    fn test_internal_define_slots() {
+        let f = compiled("(lambda (x) (define y 1) (if x (define z 2) 0) (list y z))").unwrap();
+        let f = lambda(&f);
+        // y has its slot before the body runs, z once its define is reached
+        assert_eq!(f.frame_size, 3);
+        assert!(matches!(f.body[0], Expr::DefineLocal(1, _)));
+        match &f.body[1] {
+            Expr::If(_, conseq, _) => assert!(matches!(**conseq, Expr::DefineLocal(2, _))),
+            other => panic!("not an if: {:?}", other),
+        }
+        assert!(matches!(operands(&f.body[2])[..], [Expr::Global(_), Expr::Local(0, 1), Expr::Local(0, 2)]));
+        // internal defines can refer to the ones after them
+        let g = compiled("(lambda () (define f (lambda () g)) (define g 1) (f))").unwrap();
+        let g = lambda(&g);
+        assert_eq!(g.frame_size, 2);
+        let f = lambda(operands(&g.body[0])[0]);
+        assert_eq!(f.name, Some(Sym::intern("f")));
+        assert!(matches!(f.body[..], [Expr::Local(1, 1)]));
+        // a define in a lambda redefining a parameter reuses its slot
+        assert_eq!(lambda(&compiled("(lambda (x) (define x 1) x)").unwrap()).frame_size, 1);
+    }
+
+    #[test]
+    fn test_shadowing_globals() {
+        assert!(matches!(compiled("(define car 1)").unwrap(), Expr::DefineGlobal(_, _)));
+        let f = compiled("(lambda (car) (car 1))").unwrap();
+        assert!(matches!(operands(&lambda(&f).body[0])[..], [Expr::Local(0, 0), Expr::Const(_)]));
+        let f = compiled("(lambda (x) (car x))").unwrap();
+        assert!(matches!(operands(&lambda(&f).body[0])[..], [Expr::Global(car), Expr::Local(0, 0)] if *car == Sym::intern("car")));
+        let f = compiled("(lambda (y) (set! y 1) (set! z 2))").unwrap();
+        assert!(matches!(lambda(&f).body[..], [Expr::SetLocal(0, 0, _), Expr::SetGlobal(_, _)]));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        for src in ["(quote)", "(quote 1 2)", "(if 1)", "(if 1 2 3 4)", "(define x 1 2)", "(set! x)",
+                    "(lambda (1) x)", "(lambda (x))", "(lambda x x)"] {
+            match compiled(src) {
+                Err(err) => assert!(err.to_string().starts_with("Syntax Error: "), "{}: {}", src, err),
+                Ok(expr) => panic!("{} compiled to {:?}", src, expr),
+            }
+        }
+    }
+}
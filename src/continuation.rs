@@ -0,0 +1,191 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fmt::Formatter;
+use std::rc::Rc;
+use crate::lisparse::{apply_procedure, error, Atomic, Builtin, BuiltinFn, Env, LispError, LispResult, LispType};
+use crate::vm::{self, Snapshot};
+
+/*
+(call-with-current-continuation proc) or (call/cc proc) calls proc with the
+continuation of the call/cc, a procedure that makes call/cc return its
+argument again when it is called.
+
+Continuations belong to an activation: a run of the VM, or a call of call/cc
+in the tree-walker. Calling one while its activation is still running
+unwinds to it, through the builtins in between, as `LispError::Throw`; that
+covers early exits and jumping back and forth within an activation, which is
+how generators work.
+
+The VM keeps its frames on the heap, so a continuation captured by a direct
+call of call/cc there holds a copy of them and can also be called after its
+activation has returned: the copy runs again in a new run of the VM, up to
+where the activation ended, and what it returns is what the call returns.
+The builtins that were running below it, and the before thunks of
+dynamic-wind, are not part of it. The tree-walker has its frames on the Rust
+stack, and its continuations can only be used to exit.
+ */
+
+pub struct Continuation {
+    pub(crate) activation: u64,
+    /// what to run again once the activation has returned
+    pub(crate) resume: Option<Snapshot>,
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#<continuation>")
+    }
+}
+
+thread_local! {
+    static NEXT_ACTIVATION: Cell<u64> = const { Cell::new(0) };
+    /// The activations running right now, innermost last. An activation
+    /// resumed from a snapshot can be in here more than once.
+    static LIVE: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn new_activation() -> u64 {
+    NEXT_ACTIVATION.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    })
+}
+
+/// Runs `f` as activation `id`.
+pub(crate) fn running<R>(id: u64, f: impl FnOnce() -> R) -> R {
+    LIVE.with(|live| live.borrow_mut().push(id));
+    let res = f();
+    LIVE.with(|live| live.borrow_mut().pop());
+    res
+}
+
+fn is_live(id: u64) -> bool {
+    LIVE.with(|live| live.borrow().contains(&id))
+}
+
+pub(crate) fn is_call_cc(builtin: &Builtin) -> bool {
+    matches!(builtin.f, BuiltinFn::Full(f) if std::ptr::fn_addr_eq(f, call_cc as fn(Vec<LispType>, &mut Env) -> LispResult))
+}
+
+pub(crate) fn value(k: Continuation) -> LispType {
+    LispType::Atom(Atomic::Continuation(Rc::new(k)))
+}
+
+/*
+(call/cc proc), and how the tree-walker and apply call it: the continuation
+can only be used to exit
+ */
+pub fn call_cc(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    if args.len() != 1 {
+        return error(String::from("Usage: (call/cc proc)"));
+    }
+    let id = new_activation();
+    let k = value(Continuation { activation: id, resume: None });
+    match running(id, || apply_procedure(args[0].clone(), vec![k], env)) {
+        Err(LispError::Throw(k, value)) if k.activation == id => Ok(value),
+        res => res,
+    }
+}
+
+/// Calls the continuation `k` with `args`.
+pub(crate) fn invoke(k: &Rc<Continuation>, args: Vec<LispType>, env: &mut Env) -> LispResult {
+    let value = match &args[..] {
+        [] => LispType::Atom(Atomic::nil),
+        [value] => value.clone(),
+        _ => return error(format!("A continuation takes one value, got {}", args.len())),
+    };
+    if is_live(k.activation) {
+        return Err(LispError::Throw(k.clone(), value));
+    }
+    match &k.resume {
+        Some(snapshot) => vm::resume(k.activation, snapshot, value, env),
+        None => error(String::from("This continuation can't be called after its call/cc has returned; the VM engine can resume it")),
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use crate::lisparse::*;
+
+    fn env_for(engine: Engine) -> Box<Env> {
+        let mut env = Box::new(init_env());
+        env.set_engine(engine);
+        env
+    }
+
+    #[test]
+    fn test_early_return() {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let mut env = env_for(engine);
+            Eval("(define find-first (lambda (ok? items) (call/cc (lambda (return) (map (lambda (x) (if (ok? x) (return x) nil)) items) 'none))))", &mut env);
+            assert_eq!(Eval("(find-first (lambda (x) (> x 2)) '(1 2 3 4))", &mut env), LispType::Atom(Atomic::Number(3)));
+            assert_eq!(Eval("(find-first (lambda (x) (> x 9)) '(1 2 3 4))", &mut env).to_string(), "none");
+            assert_eq!(Eval("(+ 1 (call-with-current-continuation (lambda (k) (* 10 (k 2)))))", &mut env), LispType::Atom(Atomic::Number(3)));
+            assert_eq!(Eval("(call/cc (lambda (k) 5))", &mut env), LispType::Atom(Atomic::Number(5)));
+            // leaving through dynamic-wind runs the after thunk, and guard doesn't catch it
+            Eval("(define out (open-output-string))", &mut env);
+            assert_eq!(Eval("(call/cc (lambda (k) (guard (e (t 'caught)) (dynamic-wind (lambda () nil) (lambda () (k 'left)) (lambda () (display 'after out))))))", &mut env).to_string(), "left");
+            assert_eq!(Eval("(get-output-string out)", &mut env).to_string(), "\"after\"");
+            assert!(eval_source("(call/cc 1)", &mut env).is_err());
+        }
+    }
+
+    #[test]
+    fn test_exit_from_handler() {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let mut env = env_for(engine);
+            Eval("(define safe-div (lambda (a b) (call/cc (lambda (k) (with-exception-handler (lambda (e) (k 'failed)) (lambda () (/ a b)))))))", &mut env);
+            assert_eq!(Eval("(safe-div 1 0)", &mut env).to_string(), "failed");
+            assert_eq!(Eval("(safe-div 1 2)", &mut env).to_string(), "0.5");
+            assert_eq!(Eval("(with-exception-handler (lambda (e) 0) (lambda () (call/cc (lambda (k) (with-exception-handler (lambda (e) (k 'out)) (lambda () (raise-continuable 'c)))))))", &mut env).to_string(), "out");
+        }
+    }
+
+    const GENERATOR: &str = "
+(define make-generator
+  (lambda (n)
+    (define return nil)
+    (define yield (lambda (v) (call/cc (lambda (k) (set! resume k) (return v)))))
+    (define loop (lambda (i) (if (< i n) (begin (yield i) (loop (+ i 1))) (return 'done))))
+    (define resume (lambda (ignored) (loop 0)))
+    (lambda () (call/cc (lambda (r) (set! return r) (resume nil))))))";
+
+    #[test]
+    fn test_generator() {
+        let mut env = env_for(Engine::Vm);
+        Eval(GENERATOR, &mut env);
+        Eval("(define gen (make-generator 3))", &mut env);
+        // every call is a separate run of the VM, resuming the one before
+        for expected in ["0", "1", "2", "done", "done"] {
+            assert_eq!(Eval("(gen)", &mut env).to_string(), expected);
+        }
+        // and within one run
+        Eval("(define gen4 (make-generator 4))", &mut env);
+        assert_eq!(Eval("(list (gen4) (gen4) (gen4) (gen4) (gen4))", &mut env).to_string(), "(0 1 2 3 done)");
+        // two generators don't get in each other's way
+        Eval("(define a (make-generator 2))", &mut env);
+        Eval("(define b (make-generator 2))", &mut env);
+        assert_eq!(Eval("(list (a) (b) (a) (b) (a))", &mut env).to_string(), "(0 0 1 1 done)");
+    }
+
+    #[test]
+    fn test_reentry() {
+        let mut env = env_for(Engine::Vm);
+        Eval("(define again nil)", &mut env);
+        assert_eq!(Eval("(+ 1 (call/cc (lambda (k) (set! again k) 1)))", &mut env), LispType::Atom(Atomic::Number(2)));
+        assert_eq!(Eval("(again 10)", &mut env), LispType::Atom(Atomic::Number(11)));
+        // a loop going back to the same continuation
+        Eval("(define n 0)", &mut env);
+        Eval("(define top nil)", &mut env);
+        assert_eq!(Eval("((lambda () (call/cc (lambda (k) (set! top k))) (set! n (+ n 1)) (if (< n 5) (top nil) n)))", &mut env), LispType::Atom(Atomic::Number(5)));
+        let mut env = env_for(Engine::Tree);
+        Eval("(define again nil)", &mut env);
+        Eval("(+ 1 (call/cc (lambda (k) (set! again k) 1)))", &mut env);
+        assert!(eval_source("(again 10)", &mut env).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use crate::lisparse::{apply_procedure, error, Atomic, Env, LispError, LispResult, LispType, List};
+use crate::printer;
+
+/*
+Any value can be raised. It unwinds the calls it was raised in until a
+with-exception-handler or a guard catches it:
+  (raise obj)                    raises obj
+  (raise-continuable obj)        calls the innermost handler with obj where it
+                                 was raised and returns what the handler returns
+  (error message irritant...)    raises an error object
+  (with-exception-handler handler thunk)
+                                 calls thunk; if it raises, handler is called
+                                 with what was raised, outside of thunk, and
+                                 returning from it is an error in turn
+  (guard (var clause...) body...)
+                                 the value of body, or if it raises, of the
+                                 first clause (test expr...) whose test is t
+                                 with var bound to what was raised; the
+                                 last clause can be (else expr...), and
+                                 without one what no clause matches is
+                                 raised again
+  (dynamic-wind before thunk after)
+                                 calls thunk between before and after, and
+                                 after even if thunk raises
+
+Errors of the interpreter and the builtins, like an undefined variable or
+(car 1), are caught as error objects.
+ */
+
+/// What `error` and failing builtins raise.
+#[derive(Debug)]
+pub struct Condition {
+    pub message: Rc<str>,
+    pub irritants: List,
+}
+
+impl Condition {
+    /// The message followed by the irritants, as the REPL reports it.
+    pub fn describe(&self) -> String {
+        let mut out = self.message.to_string();
+        for irritant in self.irritants.iter() {
+            out.push(' ');
+            out.push_str(&printer::write_string(irritant));
+        }
+        out
+    }
+}
+
+/// Fails with an error object, as `(error message irritant...)` does.
+pub(crate) fn error_with<T>(message: impl Into<Rc<str>>, irritants: Vec<LispType>) -> Result<T, LispError> {
+    let condition = Condition { message: message.into(), irritants: irritants.into() };
+    Err(LispError::Raise(LispType::Atom(Atomic::Condition(Rc::new(condition)))))
+}
+
+/// What was raised, with errors of the interpreter turned into error
+/// objects. A continuation on its way out isn't caught.
+pub fn condition(err: LispError) -> Result<LispType, LispError> {
+    match err {
+        LispError::Error(message) => {
+            Ok(LispType::Atom(Atomic::Condition(Rc::new(Condition { message: message.into(), irritants: List::from(vec![]) }))))
+        }
+        LispError::Raise(obj) => Ok(obj),
+        LispError::Throw(..) => Err(err),
+    }
+}
+
+#[derive(Clone)]
+enum Handler {
+    Proc(LispType),
+    Guard,
+}
+
+thread_local! {
+    /// The handlers installed by with-exception-handler and guard, innermost last.
+    static HANDLERS: RefCell<Vec<Handler>> = const { RefCell::new(Vec::new()) };
+    /// The index of a handler that failed when raise-continuable called it.
+    /// What it raised is on its way out through the with-exception-handler
+    /// that installed it, which has to let it through.
+    static FAILED_HANDLER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Calls `f` with `handler` installed, and returns the index it had.
+fn installing(handler: Handler, f: impl FnOnce() -> LispResult) -> (usize, LispResult) {
+    let depth = HANDLERS.with(|handlers| {
+        let mut handlers = handlers.borrow_mut();
+        handlers.push(handler);
+        handlers.len() - 1
+    });
+    let res = f();
+    HANDLERS.with(|handlers| handlers.borrow_mut().truncate(depth));
+    (depth, res)
+}
+
+fn arity(args: &[LispType], n: usize, usage: &str) -> Result<(), LispError> {
+    if args.len() != n {
+        return error(format!("Usage: {}", usage));
+    }
+    Ok(())
+}
+
+/*
+(raise obj)
+ */
+pub fn raise(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, "(raise obj)")?;
+    Err(LispError::Raise(args[0].clone()))
+}
+
+/*
+(raise-continuable obj)
+ */
+pub fn raise_continuable(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 1, "(raise-continuable obj)")?;
+    let handler = HANDLERS.with(|handlers| handlers.borrow().last().cloned());
+    let handler = match handler {
+        Some(Handler::Proc(handler)) => handler,
+        // a guard catches it by unwinding
+        _ => return Err(LispError::Raise(args[0].clone())),
+    };
+    // the handler runs with the handlers outside of it installed
+    let saved = HANDLERS.with(|handlers| handlers.borrow_mut().pop().unwrap());
+    let res = apply_procedure(handler, vec![args[0].clone()], env);
+    let depth = HANDLERS.with(|handlers| {
+        let mut handlers = handlers.borrow_mut();
+        handlers.push(saved);
+        handlers.len() - 1
+    });
+    if let Err(LispError::Error(_) | LispError::Raise(_)) = res {
+        FAILED_HANDLER.with(|failed| failed.set(Some(depth)));
+    }
+    res
+}
+
+/*
+(error message irritant...)
+ */
+pub fn error_builtin(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    match args.split_first() {
+        Some((LispType::Atom(Atomic::Str(message)), irritants)) => error_with(message.clone(), irritants.to_vec()),
+        _ => error(String::from("Usage: (error message irritant...), message being a string")),
+    }
+}
+
+/*
+(with-exception-handler handler thunk)
+ */
+pub fn with_exception_handler(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 2, "(with-exception-handler handler thunk)")?;
+    let handler = args[0].clone();
+    let (depth, res) = installing(Handler::Proc(handler.clone()), || apply_procedure(args[1].clone(), vec![], env));
+    let err = match res {
+        Ok(value) => return Ok(value),
+        Err(err) => err,
+    };
+    if FAILED_HANDLER.with(|failed| failed.get()) == Some(depth) {
+        FAILED_HANDLER.with(|failed| failed.set(None));
+        return Err(err);
+    }
+    let obj = condition(err)?;
+    apply_procedure(handler, vec![obj.clone()], env)?;
+    error_with("The exception handler returned from a non-continuable raise of", vec![obj])
+}
+
+/*
+(%guard thunk handler) is what guard expands to: handler is called with
+what thunk raises
+ */
+pub fn guard(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 2, "(%guard thunk handler)")?;
+    match installing(Handler::Guard, || apply_procedure(args[0].clone(), vec![], env)).1 {
+        Err(err) => apply_procedure(args[1].clone(), vec![condition(err)?], env),
+        res => res,
+    }
+}
+
+/// The macros written in Lisp. guard calls itself with the variable and
+/// the clauses to turn the clauses into ifs.
+pub const MACROS: &str = "
+(define-syntax guard
+  (syntax-rules (else)
+    ((_ (var clause ...) body ...)
+     (%guard (lambda () body ...) (lambda (var) (guard var clause ...))))
+    ((_ var) (raise var))
+    ((_ var (else expr ...)) (begin expr ...))
+    ((_ var (test) clause ...) (if test t (guard var clause ...)))
+    ((_ var (test expr ...) clause ...) (if test (begin expr ...) (guard var clause ...)))))
+";
+
+/*
+(dynamic-wind before thunk after)
+ */
+pub fn dynamic_wind(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 3, "(dynamic-wind before thunk after)")?;
+    apply_procedure(args[0].clone(), vec![], env)?;
+    let res = apply_procedure(args[1].clone(), vec![], env);
+    apply_procedure(args[2].clone(), vec![], env)?;
+    res
+}
+
+fn condition_arg<'a>(args: &'a [LispType], name: &str) -> Result<&'a Condition, LispError> {
+    match args {
+        [LispType::Atom(Atomic::Condition(condition))] => Ok(condition),
+        [other] => error_with(format!("The argument of '{}' should be an error object", name), vec![other.clone()]),
+        _ => error(format!("Usage: ({} error-object)", name)),
+    }
+}
+
+/*
+(error-object? obj)
+ */
+pub fn is_error_object(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, "(error-object? obj)")?;
+    Ok(LispType::Atom(match args[0] {
+        LispType::Atom(Atomic::Condition(_)) => Atomic::t,
+        _ => Atomic::nil,
+    }))
+}
+
+/*
+(error-object-message error-object)
+ */
+pub fn error_object_message(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    Ok(LispType::Atom(Atomic::Str(condition_arg(&args, "error-object-message")?.message.clone())))
+}
+
+/*
+(error-object-irritants error-object)
+ */
+pub fn error_object_irritants(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    Ok(LispType::List(condition_arg(&args, "error-object-irritants")?.irritants.clone()))
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use crate::lisparse::*;
+
+    fn both_engines(test: impl Fn(&mut Box<Env>)) {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let mut env = Box::new(init_env());
+            env.set_engine(engine);
+            test(&mut env);
+        }
+    }
+
+    #[test]
+    fn test_guard() {
+        both_engines(|env| {
+            assert_eq!(Eval("(guard (e (t (list 'caught e))) (+ 1 (raise 'oops)))", env).to_string(), "(caught oops)");
+            assert_eq!(Eval("(guard (e ((error-object? e) (error-object-message e))) (error \"bad\" 1 2))", env).to_string(), "\"bad\"");
+            assert_eq!(Eval("(guard (e ((error-object? e) (error-object-irritants e))) (error \"bad\" 1 \"two\"))", env).to_string(), "(1 \"two\")");
+            assert_eq!(Eval("(guard (e ((= e 1) 'one) (else 'other)) (raise 2))", env).to_string(), "other");
+            assert_eq!(Eval("(guard (e (nil 'no)) 42)", env).to_string(), "42");
+            // not caught by the inner guard, so by the outer one
+            assert_eq!(Eval("(guard (e (t (list 'outer e))) (guard (e ((= e 1) 'inner)) (raise 2)))", env).to_string(), "(outer 2)");
+            assert!(eval_source("(guard (e ((= e 1) 'one)) (raise 2))", env).is_err());
+        });
+    }
+
+    #[test]
+    fn test_builtin_errors() {
+        both_engines(|env| {
+            let message = "(lambda (thunk) (guard (e ((error-object? e) (list (error-object-message e) (error-object-irritants e)))) (thunk)))";
+            Eval(&format!("(define message {})", message), env);
+            assert_eq!(Eval("(message (lambda () (+ 1 'x)))", env).to_string(), "(\"The operands of '+' should be of type i32 or f64\" (x))");
+            assert_eq!(Eval("(message (lambda () (car 5)))", env).to_string(), "(\"The argument of 'car' should be a list\" (5))");
+            assert_eq!(Eval("(message (lambda () (/ 1 0)))", env).to_string(), "(\"Division by zero in '/'\" (1 0))");
+            assert_eq!(Eval("(message (lambda () undefined-thing))", env).to_string(), "(\"undefined-thing is not defined!\" ())");
+            assert_eq!(Eval("(guard (e (t (error-object-message e))) (* 65536 65536))", env).to_string(), "\"Integer overflow in '*'\"");
+            assert_eq!(Eval("(message (lambda () (string->symbol 'a)))", env).to_string(), "(\"The argument of 'string->symbol' should be a string\" (a))");
+            assert_eq!(Eval("(message (lambda () (symbol->string \"a\")))", env).to_string(), "(\"The argument of 'symbol->string' should be a symbol\" (\"a\"))");
+            Eval("(define defined-once 1)", env);
+            assert_eq!(eval_source("(define defined-once 2)", env).unwrap_err().to_string(), "You can't define a symbol twice defined-once");
+            assert_eq!(Eval("(message (lambda () (set! never-defined 2)))", env).to_string(), "(\"Assigning to an undefined symbol is not allowed\" (never-defined))");
+            assert_eq!(Eval("defined-once", env), LispType::Atom(Atomic::Number(1)));
+        });
+    }
+
+    #[test]
+    fn test_with_exception_handler() {
+        both_engines(|env| {
+            assert_eq!(Eval("(with-exception-handler (lambda (e) 10) (lambda () (+ 1 (raise-continuable 'c))))", env).to_string(), "11");
+            // returning from the handler of raise is an error in itself
+            assert_eq!(Eval("(guard (e (t (error-object-irritants e))) (with-exception-handler (lambda (e) 0) (lambda () (raise 'x))))", env).to_string(), "(x)");
+            Eval("(define log (open-output-string))", env);
+            assert_eq!(Eval("(guard (e (t e)) (with-exception-handler (lambda (e) (display e log) (raise 'again)) (lambda () (raise 'first))))", env).to_string(), "again");
+            // what the handler of raise-continuable raises goes to the outer handler
+            assert_eq!(Eval("(guard (e (t (list 'outer e))) (with-exception-handler (lambda (e) (display e log) (raise 'from-handler)) (lambda () (raise-continuable 'c))))", env).to_string(), "(outer from-handler)");
+            assert_eq!(Eval("(get-output-string log)", env).to_string(), "\"firstc\"");
+            assert_eq!(Eval("(guard (e (t 'guarded)) (with-exception-handler (lambda (e) 'handled) (lambda () (raise-continuable 'c))))", env).to_string(), "handled");
+            assert_eq!(Eval("(with-exception-handler (lambda (e) 'handled) (lambda () (guard (e (t 'guarded)) (raise-continuable 'c))))", env).to_string(), "guarded");
+        });
+    }
+
+    #[test]
+    fn test_dynamic_wind() {
+        both_engines(|env| {
+            Eval("(define out (open-output-string))", env);
+            Eval("(define trace (lambda (x) (lambda () (display x out))))", env);
+            assert_eq!(Eval("(dynamic-wind (trace 'before) (lambda () 'during) (trace 'after))", env).to_string(), "during");
+            assert_eq!(Eval("(guard (e (t e)) (dynamic-wind (trace '<) (lambda () (raise 'x)) (trace '>)))", env).to_string(), "x");
+            assert_eq!(Eval("(get-output-string out)", env).to_string(), "\"beforeafter<>\"");
+        });
+    }
+
+    #[test]
+    fn test_uncaught() {
+        let mut env = Box::new(init_env());
+        let err = eval_source("(error \"Something bad:\" 42 'x)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "Something bad: 42 x");
+        assert_eq!(eval_source("(raise 'oops)", &mut env).unwrap_err().to_string(), "Uncaught exception: oops");
+        assert_eq!(Eval("(guard (e (t e)) (error \"m\" 1))", &mut env).to_string(), "#<error \"m\" 1>");
+    }
+}
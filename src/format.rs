@@ -0,0 +1,260 @@
+use crate::lisparse::{error, Atomic, Env, LispError, LispResult, LispType};
+use crate::port::current_output;
+use crate::printer;
+
+/*
+(format destination control arg...) or (format control arg...)
+
+The destination is where the output goes:
+  nil, or no destination   returned as a string
+  t                        the current output port
+  a port                   that port
+
+Directives in the control string, with optional comma-separated numeric
+parameters, at most 10000, after the ~:
+  ~a   the next argument as display prints it, ~mincola pads it on the right
+  ~s   the next argument as write prints it, ~mincols pads it on the right
+  ~d   the next argument as a decimal integer, ~mincold pads it on the left
+  ~f   the next argument as a float, ~w,df with d digits after the point,
+       padded on the left to w
+  ~%   a newline
+  ~~   a tilde
+  ~{body~}   body for the elements of the next argument, a list, taking
+             its arguments from the list until it runs out
+  ~^   stops a ~{ iteration when there are no arguments left
+ */
+
+/// The largest width or precision a directive takes.
+const MAX_PARAM: usize = 10_000;
+
+/// The arguments directives take theirs from.
+struct Args<'a> {
+    items: &'a [LispType],
+    pos: usize,
+}
+
+impl<'a> Args<'a> {
+    fn next(&mut self) -> Result<&'a LispType, LispError> {
+        let arg = self.items.get(self.pos).ok_or_else(|| LispError::Error(String::from("Not enough arguments for format")))?;
+        self.pos += 1;
+        Ok(arg)
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.items.len()
+    }
+}
+
+/// Whether a control string ran to its end or stopped at `~^`.
+#[derive(PartialEq)]
+enum Flow {
+    Done,
+    Escape,
+}
+
+fn pad_left(s: String, width: Option<usize>) -> String {
+    format!("{:>1$}", s, width.unwrap_or(0))
+}
+
+fn pad_right(s: String, width: Option<usize>) -> String {
+    format!("{:<1$}", s, width.unwrap_or(0))
+}
+
+/// The index of the `~}` closing the `~{` whose body starts at `start`.
+fn closing_brace(control: &[char], start: usize) -> Result<usize, LispError> {
+    let mut depth = 0;
+    let mut i = start;
+    while i + 1 < control.len() {
+        if control[i] == '~' {
+            // skip the parameters to get to the directive
+            let mut j = i + 1;
+            while j < control.len() && (control[j].is_ascii_digit() || control[j] == ',') {
+                j += 1;
+            }
+            match control.get(j) {
+                Some('{') => depth += 1,
+                Some('}') if depth == 0 => return Ok(i),
+                Some('}') => depth -= 1,
+                _ => {}
+            }
+            i = j + 1;
+        } else {
+            i += 1;
+        }
+    }
+    error(String::from("Unterminated ~{ in format"))
+}
+
+fn run(control: &[char], args: &mut Args, out: &mut String) -> Result<Flow, LispError> {
+    let mut i = 0;
+    while i < control.len() {
+        if control[i] != '~' {
+            out.push(control[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let mut params: Vec<Option<usize>> = vec![None];
+        while let Some(c) = control.get(i).filter(|c| c.is_ascii_digit() || **c == ',') {
+            match c.to_digit(10) {
+                Some(d) => {
+                    let last = params.last_mut().unwrap();
+                    *last = last.unwrap_or(0).checked_mul(10).and_then(|n| n.checked_add(d as usize))
+                        .filter(|n| *n <= MAX_PARAM)
+                        .map(Some)
+                        .ok_or_else(|| LispError::Error(format!("Format parameters are at most {}", MAX_PARAM)))?;
+                }
+                None => params.push(None),
+            }
+            i += 1;
+        }
+        let param = |n: usize| params.get(n).copied().flatten();
+        let directive = match control.get(i) {
+            Some(c) => c.to_ascii_lowercase(),
+            None => return error(String::from("Format string ends in the middle of a directive")),
+        };
+        i += 1;
+        match directive {
+            'a' => out.push_str(&pad_right(printer::display_string(args.next()?), param(0))),
+            's' => out.push_str(&pad_right(printer::write_string(args.next()?), param(0))),
+            'd' => {
+                let s = match args.next()? {
+                    LispType::Atom(Atomic::Number(n)) => n.to_string(),
+                    other => printer::display_string(other),
+                };
+                out.push_str(&pad_left(s, param(0)));
+            }
+            'f' => {
+                let fp = match args.next()? {
+                    LispType::Atom(Atomic::Number(n)) => *n as f64,
+                    LispType::Atom(Atomic::Float(fp)) => *fp,
+                    other => return error(format!("~f needs a number, got {}", other)),
+                };
+                let s = match param(1) {
+                    Some(digits) if fp.is_finite() => format!("{:.*}", digits, fp),
+                    _ => printer::float_to_string(fp),
+                };
+                out.push_str(&pad_left(s, param(0)));
+            }
+            '%' => out.push('\n'),
+            '~' => out.push('~'),
+            '^' if args.is_empty() => return Ok(Flow::Escape),
+            '^' => {}
+            '{' => {
+                let end = closing_brace(control, i)?;
+                let body = &control[i..end];
+                let items = match args.next()? {
+                    LispType::List(items) => &items[..],
+                    LispType::Atom(Atomic::nil) => &[],
+                    other => return error(format!("~{{ needs a list, got {}", other)),
+                };
+                let mut inner = Args { items, pos: 0 };
+                while !inner.is_empty() {
+                    let before = inner.pos;
+                    if run(body, &mut inner, out)? == Flow::Escape {
+                        break;
+                    }
+                    if inner.pos == before {
+                        return error(String::from("The body of ~{ in format takes no arguments"));
+                    }
+                }
+                // the ~} and whatever parameters it has
+                i = end + 1;
+                while control.get(i).is_some_and(|c| *c != '}') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            '}' => return error(String::from("~} without ~{ in format")),
+            other => return error(format!("Unknown format directive ~{}", other)),
+        }
+    }
+    Ok(Flow::Done)
+}
+
+/// `control` with its directives filled in from `args`.
+pub fn format(control: &str, args: &[LispType]) -> Result<String, LispError> {
+    let control: Vec<char> = control.chars().collect();
+    let mut out = String::new();
+    run(&control, &mut Args { items: args, pos: 0 }, &mut out)?;
+    Ok(out)
+}
+
+/*
+(format destination control arg...) or (format control arg...)
+ */
+pub fn format_builtin(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    let (destination, rest) = match args.split_first() {
+        Some((LispType::Atom(Atomic::Str(_)), _)) => (&LispType::Atom(Atomic::nil), &args[..]),
+        Some((destination, rest)) => (destination, rest),
+        None => return error(String::from("Usage: (format [destination] control arg...)")),
+    };
+    let out = match rest.split_first() {
+        Some((LispType::Atom(Atomic::Str(control)), args)) => format(control, args)?,
+        _ => return error(String::from("The control argument of 'format' should be a string")),
+    };
+    let port = match destination {
+        LispType::Atom(Atomic::nil) => return Ok(LispType::Atom(Atomic::Str(out.into()))),
+        LispType::Atom(Atomic::t) => current_output(),
+        LispType::Atom(Atomic::Port(port)) => port.clone(),
+        other => return error(format!("{} is not a destination for format", other)),
+    };
+    port.write_str(&out)?;
+    Ok(LispType::Atom(Atomic::nil))
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::*;
+
+    fn formatted(src: &str) -> String {
+        let mut env = Box::new(init_env());
+        match eval_source(src, &mut env) {
+            Ok(LispType::Atom(Atomic::Str(s))) => s.to_string(),
+            other => panic!("{} gave {:?}", src, other),
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(formatted("(format \"~a and ~s~%\" \"str\" \"str\")"), "str and \"str\"\n");
+        assert_eq!(formatted("(format nil \"~d items, ~~~a\" 3 'x)"), "3 items, ~x");
+        assert_eq!(formatted("(format \"[~5d|~5a|~3s]\" 42 'ab \"\")"), "[   42|ab   |\"\" ]");
+        assert_eq!(formatted("(format \"~f ~,2f ~8,3f ~,1f\" 2 3.14159 -1.5 PI)"), "2.0 3.14   -1.500 3.1");
+        assert_eq!(formatted("(format \"~a\" (list 1.0 \"s\"))"), "(1.0 s)");
+        assert_eq!(format("~A~~", &[LispType::Atom(Atomic::t)]).unwrap(), "t~");
+    }
+
+    #[test]
+    fn test_iteration() {
+        assert_eq!(formatted("(format \"~{~a~^, ~}.\" '(1 2 3))"), "1, 2, 3.");
+        assert_eq!(formatted("(format \"~{<~a=~s>~}\" '(a 1 b \"2\"))"), "<a=1><b=\"2\">");
+        assert_eq!(formatted("(format \"~{~{~a~}/~}\" '((1 2) (3)))"), "12/3/");
+        assert_eq!(formatted("(format \"~{~a~}\" '())"), "");
+        assert_eq!(formatted("(format \"[~{~a~}]\" nil)"), "[]");
+        assert_eq!(formatted("(format \"~{~{~a~}/~}\" '(nil (1)))"), "/1/");
+    }
+
+    #[test]
+    fn test_destinations() {
+        let mut env = Box::new(init_env());
+        Eval("(define out (open-output-string))", &mut env);
+        assert_eq!(Eval("(format out \"~a-~a\" 1 2)", &mut env), LispType::Atom(Atomic::nil));
+        assert_eq!(Eval("(get-output-string out)", &mut env).to_string(), "\"1-2\"");
+        assert_eq!(Eval("(with-output-to-string (lambda () (format t \"~s\" 'x)))", &mut env).to_string(), "\"x\"");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut env = Box::new(init_env());
+        for src in ["(format \"~a ~a\" 1)", "(format \"~q\" 1)", "(format \"~{~a\" '(1))", "(format \"~f\" 'x)",
+                    "(format 3 \"x\")", "(format \"~{x~}\" '(1))", "(format \"~\")",
+                    "(format \"~10001a\" 1)", "(format \"~99999999999999999999999a\" 1)", "(format \"~1,99999f\" 1.5)"] {
+            assert!(eval_source(src, &mut env).is_err(), "{} didn't fail", src);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use crate::lisparse::{error, read_all, LispError};
+use crate::pretty::{self, Doc};
+
+/*
+The source formatter behind `rlisp fmt`. It reads source text into a tree
+that keeps what the reader throws away, lays it out with the rules of the
+pretty-printer and checks that the result still reads as the same forms.
+
+What is kept as written:
+  atoms and strings, so #x1F stays #x1F and "a\tb" keeps its escape
+  ; and #| |# comments, on the line they were on if they followed
+  something, on a line of their own otherwise
+  #; and the datum it comments out
+  a single empty line where there were one or more
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    /// an atom or string literal, exactly as written
+    Atom(String),
+    List(Vec<Elem>),
+    Quote(Box<Node>),
+    /// `#;` and the datum it comments out
+    DatumComment(Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Elem {
+    Node(Node),
+    /// whether it follows something else on the same line
+    Comment { text: String, trailing: bool },
+    /// one or more empty lines
+    Blank,
+}
+
+struct Scanner {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';' | '\'')
+}
+
+impl Scanner {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+    fn text(&self, start: usize) -> String {
+        self.chars[start..self.pos].iter().collect()
+    }
+    /// Skips whitespace and returns how many newlines it crossed.
+    fn skip_space(&mut self) -> usize {
+        let mut newlines = 0;
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            newlines += (c == '\n') as usize;
+            self.pos += 1;
+        }
+        newlines
+    }
+
+    /// The elements up to the closing bracket, or to the end of the input
+    /// at the top level.
+    fn elems(&mut self, top: bool) -> Result<Vec<Elem>, LispError> {
+        let mut elems = Vec::new();
+        loop {
+            let newlines = self.skip_space();
+            if newlines > 1 && !elems.is_empty() {
+                elems.push(Elem::Blank);
+            }
+            let trailing = newlines == 0 && !elems.is_empty();
+            match self.peek() {
+                None if top => return Ok(elems),
+                None => return error(String::from("Unexpected end of input")),
+                Some(')') if top => return error(String::from("Unexpected )")),
+                Some(')') => {
+                    self.pos += 1;
+                    if elems.last() == Some(&Elem::Blank) {
+                        elems.pop();
+                    }
+                    return Ok(elems);
+                }
+                Some(';') => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                    elems.push(Elem::Comment { text: self.text(start).trim_end().to_string(), trailing });
+                }
+                Some('#') if self.starts_with("#|") => {
+                    let start = self.pos;
+                    self.block_comment()?;
+                    elems.push(Elem::Comment { text: self.text(start), trailing });
+                }
+                _ => elems.push(Elem::Node(self.datum()?)),
+            }
+        }
+    }
+
+    fn block_comment(&mut self) -> Result<(), LispError> {
+        let mut depth = 0;
+        loop {
+            if self.starts_with("#|") {
+                depth += 1;
+                self.pos += 2;
+            } else if self.starts_with("|#") {
+                depth -= 1;
+                self.pos += 2;
+                if depth == 0 {
+                    return Ok(());
+                }
+            } else if self.peek().is_some() {
+                self.pos += 1;
+            } else {
+                return error(String::from("Unexpected end of input in a block comment"));
+            }
+        }
+    }
+
+    fn datum(&mut self) -> Result<Node, LispError> {
+        self.skip_space();
+        let start = self.pos;
+        match self.peek() {
+            None => error(String::from("Unexpected end of input")),
+            Some('(') => {
+                self.pos += 1;
+                Ok(Node::List(self.elems(false)?))
+            }
+            Some('\'') => {
+                self.pos += 1;
+                Ok(Node::Quote(Box::new(self.datum()?)))
+            }
+            Some('#') if self.starts_with("#;") => {
+                self.pos += 2;
+                Ok(Node::DatumComment(Box::new(self.datum()?)))
+            }
+            Some(quote @ ('"' | '|')) => {
+                self.pos += 1;
+                loop {
+                    match self.peek() {
+                        None if quote == '|' => return error(String::from("Unexpected end of input in a symbol")),
+                        None => return error(String::from("Unexpected end of input in a string")),
+                        Some(c) if c == quote => break,
+                        Some('\\') => self.pos += 2,
+                        Some(_) => self.pos += 1,
+                    }
+                }
+                self.pos += 1;
+                Ok(Node::Atom(self.text(start)))
+            }
+            Some(c) if c == ')' || c == ';' => error(format!("Expected a datum, found {}", c)),
+            Some(_) => {
+                while self.peek().is_some_and(|c| !is_delimiter(c)) {
+                    self.pos += 1;
+                }
+                Ok(Node::Atom(self.text(start)))
+            }
+        }
+    }
+}
+
+/// The length in bytes of `src` up to the end of its first datum, comments
+/// before it included, or `None` if there is nothing but comments.
+pub(crate) fn datum_len(src: &str) -> Result<Option<usize>, LispError> {
+    let mut scanner = Scanner { chars: src.chars().collect(), pos: 0 };
+    loop {
+        scanner.skip_space();
+        match scanner.peek() {
+            None => return Ok(None),
+            Some(';') => {
+                while scanner.peek().is_some_and(|c| c != '\n') {
+                    scanner.pos += 1;
+                }
+            }
+            Some('#') if scanner.starts_with("#|") => scanner.block_comment()?,
+            _ => {
+                if let Node::DatumComment(_) = scanner.datum()? {
+                    continue;
+                }
+                return Ok(Some(src.char_indices().nth(scanner.pos).map_or(src.len(), |(i, _)| i)));
+            }
+        }
+    }
+}
+
+fn node_doc(node: &Node) -> Doc {
+    match node {
+        Node::Atom(text) => Doc::text(text.as_str()),
+        Node::Quote(datum) => Doc::Concat(vec![Doc::text("'"), node_doc(datum)]),
+        Node::DatumComment(datum) => Doc::Concat(vec![Doc::text("#;"), node_doc(datum)]),
+        Node::List(elems) => {
+            let head = match elems.first() {
+                Some(Elem::Node(Node::Atom(text))) if !text.starts_with('"') && !is_number(text) => Some(text.as_str()),
+                _ => None,
+            };
+            pretty::list(head, list_items(elems, pretty::inline_items(head)))
+        }
+    }
+}
+
+fn is_number(token: &str) -> bool {
+    crate::number::parse_number(token).is_some()
+}
+
+/// The items `pretty::list` lays out: comments and empty lines are folded
+/// into the datum they come before, or after for trailing comments. The
+/// first `inline` items are followed by a space, so a comment after one of
+/// them goes before the next instead.
+fn list_items(elems: &[Elem], inline: usize) -> Vec<Doc> {
+    let mut items: Vec<Doc> = Vec::new();
+    // comments and empty lines waiting for the next datum
+    let mut pending: Vec<Doc> = Vec::new();
+    for elem in elems {
+        match elem {
+            Elem::Node(node) => {
+                pending.push(node_doc(node));
+                items.push(Doc::Concat(std::mem::take(&mut pending)));
+            }
+            Elem::Comment { text, trailing: true } if pending.is_empty() && items.len() > inline => {
+                let last = items.pop().unwrap();
+                items.push(Doc::Concat(vec![last, Doc::text(" "), Doc::text(text.as_str()), Doc::BreakParent]));
+            }
+            Elem::Comment { text, .. } => {
+                pending.push(Doc::text(text.as_str()));
+                pending.push(Doc::HardLine);
+            }
+            Elem::Blank => pending.push(Doc::HardLine),
+        }
+    }
+    if !pending.is_empty() {
+        // comments before the closing bracket
+        while matches!(pending.last(), Some(Doc::HardLine)) {
+            pending.pop();
+        }
+        items.push(Doc::Concat(pending));
+    }
+    if matches!(elems.last(), Some(Elem::Comment { .. })) {
+        // the closing bracket can't go after a comment
+        let last = items.pop().unwrap();
+        items.push(Doc::Concat(vec![last, Doc::HardLine]));
+    }
+    items
+}
+
+/// The top-level forms, one after the other, keeping empty lines and
+/// comments.
+fn file_doc(elems: &[Elem]) -> Doc {
+    let mut docs = Vec::new();
+    for elem in elems {
+        match elem {
+            Elem::Node(node) => {
+                if !docs.is_empty() {
+                    docs.push(Doc::HardLine);
+                }
+                docs.push(node_doc(node));
+            }
+            Elem::Comment { text, trailing: true } => docs.push(Doc::text(format!(" {}", text))),
+            Elem::Comment { text, .. } => {
+                if !docs.is_empty() {
+                    docs.push(Doc::HardLine);
+                }
+                docs.push(Doc::text(text.as_str()));
+            }
+            Elem::Blank => docs.push(Doc::HardLine),
+        }
+    }
+    Doc::Concat(docs)
+}
+
+/// `src` in the canonical style, lines no wider than `width` where the
+/// layout allows it. Fails if `src` can't be read.
+pub fn format_source(src: &str, width: usize) -> Result<String, LispError> {
+    let before = read_all(src)?;
+    let elems = Scanner { chars: src.chars().collect(), pos: 0 }.elems(true)?;
+    let mut out = pretty::render(&file_doc(&elems), width);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    if read_all(&out)? != before {
+        return error(String::from("Formatting would change what the source means, leaving it alone"));
+    }
+    Ok(out)
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(src: &str) -> String {
+        format_source(src, 40).unwrap()
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(format("(define   x\n  1)"), "(define x 1)\n");
+        assert_eq!(format("(define fibo (lambda (x) (if (< x 2) x (+ (fibo (- x 1)) (fibo (- x 2))))))"), "\
+(define fibo
+  (lambda (x)
+    (if (< x 2)
+        x
+        (+ (fibo (- x 1))
+           (fibo (- x 2))))))
+");
+        assert_eq!(format("'( #x1F  \"a\\tb\"   1.50 )"), "'(#x1F \"a\\tb\" 1.50)\n");
+        assert_eq!(format("'( |a  (b|   c)"), "'(|a  (b| c)\n");
+        assert_eq!(format(""), "");
+    }
+
+    #[test]
+    fn test_comments() {
+        let src = "\
+;; header
+
+(define x 1) ; one
+
+
+#| block |#
+(define f
+  (lambda (y)
+    ; own line
+    (+ x y) ; trailing
+    ))
+(list 1 #;2 3)";
+        assert_eq!(format(src), "\
+;; header
+
+(define x 1) ; one
+
+#| block |#
+(define f
+  (lambda (y)
+    ; own line
+    (+ x y) ; trailing
+    ))
+(list 1 #;2 3)
+");
+        assert_eq!(format("(a ; c\n b)"), "(a ; c\n   b)\n");
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let src = "(define  f (lambda (a b) ; sum\n\n (+ a b)))\n\n\n; end\n";
+        let once = format(src);
+        assert_eq!(format(&once), once);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(format_source("(define x", 80).is_err());
+        assert!(format_source("(a))", 80).is_err());
+        assert!(format_source("'|a b", 80).is_err());
+    }
+}
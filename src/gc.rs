@@ -0,0 +1,267 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use crate::lisparse::{Atomic, Frame, LispType, Proc};
+use crate::symbol::Sym;
+
+/*
+Frames and closures are reference counted, which frees everything except
+cycles: a closure stored in a slot of the frame it captured keeps that frame
+alive forever. Every frame and closure is registered here, and `collect`
+finds the ones that are only reachable from each other.
+
+It can't see the Rust stack, so instead of scanning roots it works out which
+objects are referenced from outside the heap: an object whose strong count is
+higher than the number of references other heap objects hold to it must be
+referenced from somewhere else (the global Env, a value being evaluated, the
+VM stack...). Everything reachable from those is live; the rest is garbage
+and gets its slots cleared, which breaks the cycles. Only frames can be
+mutated, so every cycle goes through one.
+
+Lists are not tracked; their elements are traced as part of whatever holds
+the list. A list can be shared with values outside the heap, so references
+through a shared list are never subtracted, which keeps what it holds alive.
+ */
+
+const MIN_THRESHOLD: usize = 1024;
+
+#[derive(Clone, Copy)]
+enum Object<'a> {
+    Frame(&'a Rc<Frame>),
+    Proc(&'a Rc<Proc>),
+}
+
+#[derive(Default)]
+struct Heap {
+    frames: Vec<Weak<Frame>>,
+    procs: Vec<Weak<Proc>>,
+    allocated: usize,
+    threshold: usize,
+    collections: usize,
+    freed: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap { threshold: MIN_THRESHOLD, ..Heap::default() });
+}
+
+pub(crate) fn track_frame(frame: &Rc<Frame>) {
+    if HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.frames.push(Rc::downgrade(frame));
+        heap.allocated += 1;
+        heap.allocated >= heap.threshold
+    }) {
+        collect();
+    }
+}
+
+pub(crate) fn track_proc(proc: &Rc<Proc>) {
+    if HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.procs.push(Rc::downgrade(proc));
+        heap.allocated += 1;
+        heap.allocated >= heap.threshold
+    }) {
+        collect();
+    }
+}
+
+fn key<T>(rc: &Rc<T>) -> *const () {
+    Rc::as_ptr(rc) as *const ()
+}
+
+/// Calls `visit` for every heap object directly referenced by `value`,
+/// skipping the contents of shared lists unless `shared` is set.
+fn trace_value<'a>(value: &'a LispType, shared: bool, visit: &mut dyn FnMut(Object<'a>)) {
+    match value {
+        LispType::Atom(Atomic::Proc(uf)) => visit(Object::Proc(uf)),
+        // like a list, a continuation can be shared with values outside the heap
+        LispType::Atom(Atomic::Continuation(k)) if shared || Rc::strong_count(k) == 1 => {
+            if let Some(snapshot) = &k.resume {
+                for frame in snapshot.frames() {
+                    visit(Object::Frame(frame));
+                }
+                for value in snapshot.values() {
+                    trace_value(value, shared, visit);
+                }
+            }
+        }
+        LispType::List(list) if shared || !list.is_shared() => {
+            for elem in list {
+                trace_value(elem, shared, visit);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Calls `visit` for every heap object referenced by `object`. Returns false
+/// if the frame is being mutated right now and can't be inspected.
+fn trace(object: Object, shared: bool, visit: &mut dyn FnMut(Object)) -> bool {
+    match object {
+        Object::Frame(frame) => {
+            if let Some(parent) = &frame.parent {
+                visit(Object::Frame(parent));
+            }
+            match frame.slots.try_borrow() {
+                Ok(slots) => {
+                    for value in slots.iter() {
+                        trace_value(value, shared, visit);
+                    }
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        Object::Proc(uf) => {
+            if let Some(env) = &uf.env {
+                visit(Object::Frame(env));
+            }
+            true
+        }
+    }
+}
+
+/// Runs a full collection and returns the number of frames and closures freed.
+pub fn collect() -> usize {
+    let (frames, procs): (Vec<Rc<Frame>>, Vec<Rc<Proc>>) = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.frames.retain(|w| w.strong_count() > 0);
+        heap.procs.retain(|w| w.strong_count() > 0);
+        (heap.frames.iter().filter_map(Weak::upgrade).collect(), heap.procs.iter().filter_map(Weak::upgrade).collect())
+    });
+    let objects: Vec<Object> = frames.iter().map(Object::Frame).chain(procs.iter().map(Object::Proc)).collect();
+    let index: HashMap<*const (), usize> = objects.iter().enumerate().map(|(i, object)| match object {
+        Object::Frame(frame) => (key(frame), i),
+        Object::Proc(uf) => (key(uf), i),
+    }).collect();
+    let index_of = |object: Object| match object {
+        Object::Frame(frame) => index[&key(frame)],
+        Object::Proc(uf) => index[&key(uf)],
+    };
+
+    // references from outside the heap, not counting the ones `objects` holds
+    let mut external: Vec<usize> = objects.iter().map(|object| match object {
+        Object::Frame(frame) => Rc::strong_count(frame) - 1,
+        Object::Proc(uf) => Rc::strong_count(uf) - 1,
+    }).collect();
+    for (i, object) in objects.iter().enumerate() {
+        if !trace(*object, false, &mut |target| external[index_of(target)] -= 1) {
+            external[i] = usize::MAX / 2;
+        }
+    }
+
+    let mut live = vec![false; objects.len()];
+    let mut pending: Vec<usize> = (0..objects.len()).filter(|i| external[*i] > 0).collect();
+    while let Some(i) = pending.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        trace(objects[i], true, &mut |target| {
+            let j = index_of(target);
+            if !live[j] {
+                pending.push(j);
+            }
+        });
+    }
+
+    let mut garbage = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        if let (false, Object::Frame(frame)) = (live[i], object) {
+            garbage.push(std::mem::take(&mut *frame.slots.borrow_mut()));
+        }
+    }
+    let freed = live.iter().filter(|live| !**live).count();
+    let survivors = objects.len() - freed;
+    drop(garbage);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated = 0;
+        heap.threshold = MIN_THRESHOLD.max(2 * survivors);
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+    freed
+}
+
+fn stats() -> LispType {
+    let entry = |name: &str, n: usize| LispType::List(vec![
+        LispType::Atom(Atomic::Symbol(Sym::intern(name))),
+        LispType::Atom(Atomic::Number(n as i32)),
+    ].into());
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let frames = heap.frames.iter().filter(|w| w.strong_count() > 0).count();
+        let procs = heap.procs.iter().filter(|w| w.strong_count() > 0).count();
+        LispType::List(vec![
+            entry("frames", frames),
+            entry("closures", procs),
+            entry("collections", heap.collections),
+            entry("freed", heap.freed),
+        ].into())
+    })
+}
+
+/*
+(gc) collects garbage cycles and returns the statistics of the heap after it
+ */
+pub fn gc(_args: Vec<LispType>) -> LispType {
+    collect();
+    stats()
+}
+
+/*
+(gc-stats) returns ((frames n) (closures n) (collections n) (freed n))
+ */
+pub fn gc_stats(_args: Vec<LispType>) -> LispType {
+    stats()
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use crate::lisparse::*;
+
+    fn live_frames(env: &mut Box<Env>) -> i32 {
+        match Eval("(car (cdr (car (gc))))", env) {
+            LispType::Atom(Atomic::Number(n)) => n,
+            other => panic!("unexpected stats {}", other),
+        }
+    }
+
+    #[test]
+    fn test_collects_cycles() {
+        let mut env = Box::new(init_env());
+        Eval("(define make-loop (lambda (n) (define loop (lambda (i) (if (= i 0) 0 (loop (- i 1))))) (loop n)))", &mut env);
+        let before = live_frames(&mut env);
+        for _ in 0..50 {
+            Eval("(make-loop 3)", &mut env);
+        }
+        assert_eq!(live_frames(&mut env), before);
+    }
+
+    #[test]
+    fn test_keeps_reachable_closures() {
+        let mut env = Box::new(init_env());
+        Eval("(define make-counter (lambda () (define n 0) (define inc (lambda () (set! n (+ n 1)) n)) inc))", &mut env);
+        Eval("(define c (make-counter))", &mut env);
+        Eval("(c)", &mut env);
+        Eval("(gc)", &mut env);
+        assert_eq!(Eval("(c)", &mut env), LispType::Atom(Atomic::Number(2)));
+        assert_eq!(Eval("(car (car (gc-stats)))", &mut env).to_string(), "frames");
+    }
+
+    #[test]
+    fn test_keeps_closures_in_shared_lists() {
+        let mut env = Box::new(init_env());
+        Eval("(define make-counter (lambda () (define n 0) (define l (list (lambda () (set! n (+ n 1)) n))) l))", &mut env);
+        Eval("(define cs (make-counter))", &mut env);
+        Eval("(gc)", &mut env);
+        assert_eq!(Eval("((car cs))", &mut env), LispType::Atom(Atomic::Number(1)));
+    }
+}
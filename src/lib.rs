@@ -0,0 +1,15 @@
+pub mod backtrace;
+pub mod compile;
+pub mod continuation;
+pub mod exception;
+pub mod format;
+pub mod formatter;
+pub mod gc;
+pub mod lisparse;
+pub mod number;
+pub mod port;
+pub mod pretty;
+pub mod printer;
+pub mod symbol;
+pub mod syntax;
+pub mod vm;
@@ -1,7 +1,21 @@
 use std::{collections::HashMap, fmt};
+use std::cell::RefCell;
 use std::fmt::{Formatter};
-use regex::Regex;
+use std::rc::Rc;
 use colored::Colorize;
+use crate::backtrace::{self, Callee, Calls, Span};
+use crate::compile::{compile, Expr, Lambda};
+use crate::continuation::{self, Continuation};
+use crate::exception::{self, Condition};
+use crate::format;
+use crate::gc;
+use crate::number::parse_number;
+use crate::port::{self, Port};
+use crate::pretty;
+use crate::printer::{self, Style};
+use crate::symbol::Sym;
+use crate::syntax::{SpecialForm, SyntaxEnv};
+use crate::vm;
 
 macro_rules! lisp_atom {
     ($var:expr, $atom_type:ident) => {
@@ -14,36 +28,83 @@ macro_rules! lisp_atom {
 pub enum Atomic {
     Number(i32),
     Float(f64),
-    Symbol(String),
-    Fun(fn(Vec<LispType>)->LispType),
-    Proc(Box<Proc>),
+    Symbol(Sym),
+    Str(Rc<str>),
+    Fun(Builtin),
+    Proc(Rc<Proc>),
+    Port(Rc<Port>),
+    /// what `error` and failing builtins raise
+    Condition(Rc<Condition>),
+    Continuation(Rc<Continuation>),
+    Special(SpecialForm),
+    /// what reading past the end of input returns
+    Eof,
     nil,
     t
 }
 
 impl fmt::Display for Atomic {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Atomic::Number(n) => write!(f, "{}", n),
-            Atomic::Float(fp) => write!(f, "{}", fp),
-            Atomic::Symbol(sym) => write!(f, "{}", sym),
-            Atomic::nil => write!(f, "nil"),
-            Atomic::t => write!(f, "t"),
-            Atomic::Fun(_f) => write!(f, "{:?}", _f),
-            Atomic::Proc(uf) => write!(f, "{:?}", uf)
+        write!(f, "{}", printer::atom_to_string(self, Style::Write))
+    }
+}
+
+/// A procedure implemented in Rust, with the name it is bound to.
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub f: BuiltinFn
+}
+
+#[derive(Clone, Copy)]
+pub enum BuiltinFn {
+    /// reports its own errors and returns nil
+    Simple(fn(Vec<LispType>)->LispType),
+    /// fails with an error, and can call procedures in the environment
+    Full(fn(Vec<LispType>, &mut Env)->LispResult)
+}
+
+impl Builtin {
+    pub fn call(&self, args: Vec<LispType>, env: &mut Env) ->LispResult {
+        match self.f {
+            BuiltinFn::Simple(f) => Ok(f(args)),
+            BuiltinFn::Full(f) => f(args, env)
+        }
+    }
+}
+
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.f, other.f) {
+            (BuiltinFn::Simple(f1), BuiltinFn::Simple(f2)) => std::ptr::fn_addr_eq(f1, f2),
+            (BuiltinFn::Full(f1), BuiltinFn::Full(f2)) => std::ptr::fn_addr_eq(f1, f2),
+            _ => false
         }
     }
 }
 
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#<builtin {}>", self.name)
+    }
+}
+
 impl std::cmp::PartialEq for Atomic {
     fn eq(&self, other: &Self) -> bool {
         match (&self, &other) {
             (&Atomic::Number(n1), &Atomic::Number(n2)) => n1==n2,
             (&Atomic::Float(fp1), &Atomic::Float(fp2)) => fp1==fp2,
             (&Atomic::Symbol(s1), &Atomic::Symbol(s2)) => s1==s2,
+            (&Atomic::Str(s1), &Atomic::Str(s2)) => s1==s2,
             (&Atomic::nil, &Atomic::nil) => true,
+            (&Atomic::Eof, &Atomic::Eof) => true,
             (&Atomic::Fun(f1), &Atomic::Fun(f2)) => f1==f2,
+            (&Atomic::Proc(p1), &Atomic::Proc(p2)) => Rc::ptr_eq(p1, p2),
+            (&Atomic::Port(p1), &Atomic::Port(p2)) => Rc::ptr_eq(p1, p2),
+            (&Atomic::Condition(c1), &Atomic::Condition(c2)) => Rc::ptr_eq(c1, c2),
+            (&Atomic::Continuation(k1), &Atomic::Continuation(k2)) => Rc::ptr_eq(k1, k2),
             (&Atomic::t, &Atomic::t) => true,
+            (&Atomic::Special(f1), &Atomic::Special(f2)) => f1==f2,
             _ => false
         }
     }
@@ -52,7 +113,76 @@ impl std::cmp::PartialEq for Atomic {
 #[derive(Debug, Clone, PartialEq)]
 pub enum LispType {
     Atom(Atomic),
-    List(Vec<LispType>)
+    List(List)
+}
+
+/// A list shared by reference, so copying a value never copies its elements.
+/// It is a view of the shared elements from `start` on, which makes `cdr` a
+/// new view instead of a new list. A list read from source text knows where
+/// it starts there.
+#[derive(Clone)]
+pub struct List {
+    items: Rc<Vec<LispType>>,
+    start: usize,
+    span: Option<Rc<Span>>
+}
+
+impl List {
+    /// The list without its first element.
+    pub fn tail(&self) ->List {
+        List{items: self.items.clone(), start: (self.start+1).min(self.items.len()), span: None}
+    }
+    /// Where the list was read from, if it was.
+    pub fn span(&self) ->Option<Rc<Span>> {
+        self.span.clone()
+    }
+    pub(crate) fn with_span(mut self, span: Option<Rc<Span>>) ->List {
+        self.span = span;
+        self
+    }
+    /// Whether other values hold the same elements.
+    pub(crate) fn is_shared(&self) ->bool {
+        Rc::strong_count(&self.items) > 1
+    }
+}
+
+impl From<Vec<LispType>> for List {
+    fn from(items: Vec<LispType>) -> Self {
+        List{items: Rc::new(items), start: 0, span: None}
+    }
+}
+
+impl FromIterator<LispType> for List {
+    fn from_iter<I: IntoIterator<Item=LispType>>(iter: I) -> Self {
+        List::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl std::ops::Deref for List {
+    type Target = [LispType];
+    fn deref(&self) -> &[LispType] {
+        &self.items[self.start..]
+    }
+}
+
+impl<'a> IntoIterator for &'a List {
+    type Item = &'a LispType;
+    type IntoIter = std::slice::Iter<'a, LispType>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for List {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
 }
 
 impl fmt::Display for LispType {
@@ -61,713 +191,1036 @@ impl fmt::Display for LispType {
     }
 }
 
+/// Splits source text into tokens. A quote is rewritten to `( quote <datum> )`
+/// here so that `cvt_to_nested_expression` only has to deal with brackets,
+/// and a string literal is kept as one token, quotes included. Comments
+/// (`; line`, nestable `#| block |#` and `#;` before a datum) are dropped.
 pub fn split_cmd_to_vec(cmd: &str) -> Vec<String> {
-    let expanded_cmd = &cmd.trim().replace("(", "( ")
-                                        .replace(")", " )")
-                                        .replace("'(", "'( ")[..];
-    let splited_cmd: Vec<String> = expanded_cmd.split_ascii_whitespace()
-                                                .map(str::to_string)
-                                                .collect();
-    let mut splited_cmd_: Vec<String> = Vec::new();
-    let mut bracket_v: Vec<&str> = Vec::new();
-    for v in splited_cmd{
-        match v.as_str() {
-            "'(" => {
-                splited_cmd_.push(String::from("("));
-                splited_cmd_.push(String::from("quote"));
-                splited_cmd_.push(String::from("("));
-                bracket_v.push("'(");
+    tokenize(cmd).0.text
+}
+
+/// Whether `cmd` is missing the end of a form: a closing bracket, the
+/// datum after a quote or `#;`, or the end of a string or block comment.
+pub fn is_incomplete(cmd: &str) -> bool {
+    !tokenize(cmd).1
+}
+
+/// Tokens along with the byte offset each of them starts at.
+#[derive(Default)]
+struct Tokens {
+    text: Vec<String>,
+    at: Vec<usize>
+}
+
+impl Tokens {
+    fn push(&mut self, token: String, at: usize) {
+        self.text.push(token);
+        self.at.push(at);
+    }
+    /// Closes a quote, which has no place of its own in the source.
+    fn close(&mut self) {
+        let at = self.at.last().copied().unwrap_or(0);
+        self.push(String::from(")"), at);
+    }
+    fn truncate(&mut self, len: usize) {
+        self.text.truncate(len);
+        self.at.truncate(len);
+    }
+}
+
+fn tokenize(cmd: &str) -> (Tokens, bool) {
+    enum Open { Paren, Quote, Comment(usize) }
+    // a finished datum also finishes the quotes waiting for it, unless it
+    // was commented out, which throws away its tokens instead
+    fn datum_done(tokens: &mut Tokens, opened: &mut Vec<Open>) {
+        while let Some(open) = opened.last() {
+            match open {
+                Open::Paren => return,
+                Open::Quote => tokens.close(),
+                Open::Comment(start) => {
+                    tokens.truncate(*start);
+                    opened.pop();
+                    return;
+                }
+            }
+            opened.pop();
+        }
+    }
+    fn next(chars: &mut std::iter::Peekable<std::str::CharIndices>) -> Option<char> {
+        chars.next().map(|(_, c)| c)
+    }
+    // closes whatever is still waiting for a datum
+    fn unwind(tokens: &mut Tokens, opened: &mut Vec<Open>) {
+        while !matches!(opened.last(), Some(Open::Paren) | None) {
+            datum_done(tokens, opened);
+        }
+    }
+    let mut tokens = Tokens::default();
+    let mut opened: Vec<Open> = Vec::new();
+    let mut complete = true;
+    let mut chars = cmd.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '(' => {
+                tokens.push(String::from("("), at);
+                opened.push(Open::Paren);
             },
-            "(" => {
-                splited_cmd_.push(String::from("("));
-                bracket_v.push("(");
+            ')' => {
+                unwind(&mut tokens, &mut opened);
+                if let Some(Open::Paren) = opened.pop() {
+                    tokens.push(String::from(")"), at);
+                    datum_done(&mut tokens, &mut opened);
+                }
             },
-            ")" => {
-                match bracket_v.pop() {
-                    Some("(") => splited_cmd_.push(String::from(")")),
-                    Some("'(") => {
-                        splited_cmd_.push(String::from(")"));
-                        splited_cmd_.push(String::from(")"));
+            '\'' => {
+                tokens.push(String::from("("), at);
+                tokens.push(String::from("quote"), at);
+                opened.push(Open::Quote);
+            },
+            '"' => {
+                let mut token = String::from("\"");
+                complete = false;
+                while let Some(c) = next(&mut chars) {
+                    token.push(c);
+                    match c {
+                        '\\' => token.extend(next(&mut chars)),
+                        '"' => {
+                            complete = true;
+                            break;
+                        },
+                        _ => {}
                     }
-                    _ => {}
                 }
-            }
+                tokens.push(token, at);
+                datum_done(&mut tokens, &mut opened);
+            },
+            '|' => {
+                let mut token = String::from("|");
+                complete = false;
+                while let Some(c) = next(&mut chars) {
+                    token.push(c);
+                    match c {
+                        '\\' => token.extend(next(&mut chars)),
+                        '|' => {
+                            complete = true;
+                            break;
+                        },
+                        _ => {}
+                    }
+                }
+                tokens.push(token, at);
+                datum_done(&mut tokens, &mut opened);
+            },
+            ';' => {
+                while let Some(c) = next(&mut chars) {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            },
+            '#' if matches!(chars.peek(), Some((_, '|'))) => {
+                chars.next();
+                let mut depth = 1;
+                while depth > 0 {
+                    match (next(&mut chars), chars.peek().map(|&(_, c)| c)) {
+                        (Some('|'), Some('#')) => { chars.next(); depth -= 1; },
+                        (Some('#'), Some('|')) => { chars.next(); depth += 1; },
+                        (Some(_), _) => {},
+                        (None, _) => {
+                            complete = false;
+                            break;
+                        }
+                    }
+                }
+            },
+            '#' if matches!(chars.peek(), Some((_, ';'))) => {
+                chars.next();
+                opened.push(Open::Comment(tokens.text.len()));
+            },
+            c if c.is_whitespace() => {},
             _ => {
-                if v.starts_with("'"){
-                    splited_cmd_.push(String::from("("));
-                    splited_cmd_.push(String::from("quote"));
-                    splited_cmd_.push(String::from(&v[1..]));
-                    splited_cmd_.push(String::from(")"));
-                } else {
-                    splited_cmd_.push(v);
+                let mut token = String::from(c);
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' || c == ';' {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
                 }
+                tokens.push(token, at);
+                datum_done(&mut tokens, &mut opened);
             }
         }
     }
-    return splited_cmd_;
+    complete &= opened.is_empty();
+    unwind(&mut tokens, &mut opened);
+    (tokens, complete)
+}
+
+/// Turns a string token, quotes included, into its contents.
+fn parse_string_literal(token: &str) ->String {
+    unescape(token, '"')
+}
+
+/// Turns a `|...|` symbol token, bars included, into the symbol's name.
+fn parse_symbol_literal(token: &str) ->String {
+    unescape(token, '|')
+}
+
+fn unescape(token: &str, delimiter: char) ->String {
+    let body = token.strip_prefix(delimiter).unwrap_or(token);
+    let body = body.strip_suffix(delimiter).unwrap_or(body);
+    let mut res = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some(c) => res.push(c),
+            None => {}
+        }
+    }
+    res
+}
+
+pub fn cvt_to_nested_expression(splited_cmd: &[String], idx: &mut usize, depth: i32) ->LispResult {
+    parse(splited_cmd, idx, depth, &|_| None)
 }
 
-pub fn cvt_to_nested_expression(splited_cmd: &[String], idx: &mut usize, depth: i32) ->LispType {
+/// Reads the tokens from `idx` on; the lists get the span `span_of` gives
+/// for the index of their opening bracket.
+fn parse(tokens: &[String], idx: &mut usize, depth: i32, span_of: &dyn Fn(usize) -> Option<Rc<Span>>) ->LispResult {
     let mut tv: Vec<LispType> = Vec::new();
-    while *idx < splited_cmd.len() {
-        let content = &splited_cmd[*idx];
+    while *idx < tokens.len() {
+        let content = &tokens[*idx];
         *idx += 1;
         let item = match &content[..] {
-            "(" => cvt_to_nested_expression(splited_cmd, idx, depth+1),
-            ")" => {return LispType::List(tv);},
+            "(" => {
+                let open = *idx - 1;
+                match parse(tokens, idx, depth+1, span_of)? {
+                    LispType::List(list) => LispType::List(list.with_span(span_of(open))),
+                    item => item
+                }
+            },
+            ")" => {return Ok(LispType::List(tv.into()));},
             "nil" => { LispType::Atom(Atomic::nil) },
             "t" => { LispType::Atom(Atomic::t) },
-            _ => match is_number(&content) {
-                        1 => LispType::Atom(Atomic::Number(content.parse::<i32>().unwrap())),
-                        2 => LispType::Atom(Atomic::Float(content.parse::<f64>().unwrap())),
-                        _ => LispType::Atom(Atomic::Symbol(content.clone()))
+            _ if content.starts_with('"') => LispType::Atom(Atomic::Str(parse_string_literal(content).into())),
+            _ if content.starts_with('|') => LispType::Atom(Atomic::Symbol(Sym::intern(&parse_symbol_literal(content)))),
+            _ if content.starts_with("#<") => return error(format!("Unreadable object {}", content)),
+            _ => match parse_number(content) {
+                        Some(number) => LispType::Atom(number?),
+                        None => LispType::Atom(Atomic::Symbol(Sym::intern(content)))
                     }
         };
         tv.push(item);
     }
-    if depth==0 { tv.last().unwrap().clone() } else { LispType::List(tv) }
+    Ok(if depth==0 { tv.pop().unwrap_or(LispType::Atom(Atomic::nil)) } else { LispType::List(tv.into()) })
+}
+
+/// Reads every top-level datum of `cmd`.
+pub fn read_all(cmd: &str) ->Result<Vec<LispType>, LispError> {
+    read_source(cmd, "<input>")
+}
+
+/// Reads every top-level datum of `cmd`, with the lists knowing where they
+/// are in `source`, e.g. a file name.
+pub fn read_source(cmd: &str, source: &str) ->Result<Vec<LispType>, LispError> {
+    let (tokens, complete) = tokenize(cmd);
+    if !complete {
+        return error(String::from("Unexpected end of input"));
+    }
+    let source: Rc<str> = Rc::from(source);
+    let line_starts: Vec<usize> = std::iter::once(0).chain(cmd.match_indices('\n').map(|(at, _)| at + 1)).collect();
+    let span_of = |idx: usize| {
+        let at = tokens.at[idx];
+        let line = line_starts.partition_point(|&start| start <= at);
+        let column = cmd[line_starts[line - 1]..at].chars().count() + 1;
+        Some(Rc::new(Span { source: source.clone(), line, column }))
+    };
+    // the tokenizer never leaves a stray ")", so at depth 1 this reads to the end
+    match parse(&tokens.text, &mut 0, 1, &span_of)? {
+        LispType::List(forms) => Ok(forms.to_vec()),
+        _ => unreachable!()
+    }
 }
 
+/// `expr` as `write` prints it, see `printer`.
 pub fn expr2str(expr: &LispType) ->String {
-    match expr {
-        LispType::Atom(atom) => { format!("{}", atom) },
-        LispType::List(list) => {
-            let mut stringv: Vec<String> = Vec::new();
-            for elem in list{
-                stringv.push(expr2str(elem));
-            }
-            format!("({})", stringv.join(" "))
+    printer::write_string(expr)
+}
+
+#[derive(Debug, Clone)]
+pub enum LispError {
+    Error(String),
+    /// a value raised and not caught yet
+    Raise(LispType),
+    /// a continuation called with a value, on the way to its activation
+    Throw(Rc<Continuation>, LispType),
+}
+
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LispError::Error(msg) => write!(f, "{}", msg),
+            LispError::Raise(LispType::Atom(Atomic::Condition(condition))) => write!(f, "{}", condition.describe()),
+            LispError::Raise(obj) => write!(f, "Uncaught exception: {}", obj),
+            LispError::Throw(..) => write!(f, "A continuation was called outside of its activation"),
         }
     }
 }
 
-pub fn is_number(s: &String) ->i32 {
-    if Regex::new(r"^-?\d+$").unwrap().is_match(&s){
-        return 1;
+pub type LispResult = Result<LispType, LispError>;
+
+pub(crate) fn error<T>(msg: String) -> Result<T, LispError> {
+    Err(LispError::Error(msg))
+}
+
+/// A run-time frame of a procedure call, addressed by the (depth, index)
+/// pairs the compiler resolved.
+pub struct Frame {
+    pub(crate) slots: RefCell<Vec<LispType>>,
+    pub(crate) parent: Option<Rc<Frame>>
+}
+
+impl fmt::Debug for Frame {
+    // frames may reach themselves through closures, so don't print the slots
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#<frame of {} slots>", self.slots.borrow().len())
+    }
+}
+
+impl Frame {
+    pub(crate) fn new(slots: Vec<LispType>, parent: Option<Rc<Frame>>) ->Rc<Frame> {
+        let frame = Rc::new(Frame{slots: RefCell::new(slots), parent});
+        gc::track_frame(&frame);
+        frame
     }
-    else if Regex::new(r"^[-+]?[0-9]*\.?[0-9]+$").unwrap().is_match(&s) {
-        return 2;
+    pub(crate) fn ancestor(frame: &Rc<Frame>, depth: usize) ->&Rc<Frame> {
+        let mut frame = frame;
+        for _ in 0..depth {
+            frame = frame.parent.as_ref().expect("compiler resolved a frame that doesn't exist");
+        }
+        frame
     }
-    0
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Proc {
-    params: Vec<String>,
-    expr: LispType,
-    parent_env: Box<Env>
+    pub(crate) lambda: Rc<Lambda>,
+    pub(crate) env: Option<Rc<Frame>>
 }
 
 impl Proc {
-    fn new(params: Vec<String>, expr:LispType, parent_env: Box<Env>) ->Proc {
-        return Proc{params, expr, parent_env};
+    pub(crate) fn new(lambda: Rc<Lambda>, env: Option<Rc<Frame>>) ->Rc<Proc> {
+        let proc = Rc::new(Proc{lambda, env});
+        gc::track_proc(&proc);
+        proc
     }
-    fn run(&mut self, args: Vec<LispType>, parent_env: Box<Env>) ->LispType{
-        if args.len()!=self.params.len() {
-            println!("{}","Unmatched arguments with parameters".red());
-        } else {
-            let mut child_env = Box::new(Env::new(Some(parent_env)));
-            for i in 0..args.len(){
-                child_env.add_symbol(&self.params[i], &args[i]);
-            }
-            return eval(&self.expr, &mut child_env);
+    /// How a call looks, e.g. `(square x)`.
+    pub fn signature(&self) ->String {
+        let name = self.lambda.name.map(|name| name.name()).unwrap_or_else(|| Rc::from("lambda"));
+        let params: Vec<String> = self.lambda.params.iter().map(|param| param.name().to_string()).collect();
+        format!("({})", std::iter::once(name.to_string()).chain(params).collect::<Vec<_>>().join(" "))
+    }
+    pub(crate) fn bind(&self, args: Vec<LispType>) ->Result<Rc<Frame>, LispError> {
+        if args.len()!=self.lambda.params.len() {
+            let name = self.lambda.name.map(|name| name.name()).unwrap_or_else(|| Rc::from("lambda"));
+            return error(format!("Unmatched arguments with parameters of {}: expected {}, got {}", name, self.lambda.params.len(), args.len()));
         }
-        LispType::Atom(Atomic::nil)
+        let mut slots = args;
+        slots.resize(self.lambda.frame_size, LispType::Atom(Atomic::nil));
+        Ok(Frame::new(slots, self.env.clone()))
     }
 }
 
+/// Which backend runs compiled code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// walks the AST directly
+    Tree,
+    /// compiles to bytecode for the stack machine in `vm`
+    Vm
+}
+
+/// The global environment. Local variables live in `Frame`s.
 #[derive(Debug,Clone)]
 pub struct Env {
-    local_env: HashMap<String, LispType>,
-    parent_env: Option<Box<Env>>
+    local_env: HashMap<Sym, LispType>,
+    syntax: Rc<RefCell<SyntaxEnv>>,
+    engine: Engine,
+    pub(crate) calls: Calls
 }
 
 impl Env {
-    fn new(_parent_env: Option<Box<Env>>) ->Env{
-        return Env{
+    fn new() ->Env{
+        Env{
             local_env: HashMap::new(),
-            parent_env: _parent_env
+            syntax: Rc::new(RefCell::new(SyntaxEnv::default())),
+            engine: Engine::Tree,
+            calls: Calls::default()
         }
     }
-    fn add_symbol(&mut self, sym: &String, value: &LispType) {
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+    pub fn engine(&self) ->Engine {
+        self.engine
+    }
+    /// The global bindings, sorted by name.
+    pub fn globals(&self) ->Vec<(Rc<str>, LispType)> {
+        let mut globals: Vec<_> = self.local_env.iter().map(|(sym, value)| (sym.name(), value.clone())).collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+    pub fn global(&self, name: &str) ->Option<LispType> {
+        self.local_env.get(&Sym::intern(name)).cloned()
+    }
+    /// Names of the global variables, builtins included.
+    pub fn global_names(&self) ->Vec<Rc<str>> {
+        self.local_env.keys().map(|sym| sym.name()).collect()
+    }
+    /// Names of the special forms and macros.
+    pub fn keyword_names(&self) ->Vec<Rc<str>> {
+        self.syntax.borrow().keywords().map(|sym| sym.name()).collect()
+    }
+    /// Binds `name` to a procedure implemented in Rust.
+    pub fn add_builtin(&mut self, name: &'static str, f: fn(Vec<LispType>)->LispType) {
+        self.local_env.insert(Sym::intern(name), LispType::Atom(Atomic::Fun(Builtin{name, f: BuiltinFn::Simple(f)})));
+    }
+    /// Binds `name` to a procedure implemented in Rust that can fail or
+    /// call other procedures.
+    pub fn add_full_builtin(&mut self, name: &'static str, f: fn(Vec<LispType>, &mut Env)->LispResult) {
+        self.local_env.insert(Sym::intern(name), LispType::Atom(Atomic::Fun(Builtin{name, f: BuiltinFn::Full(f)})));
+    }
+    pub(crate) fn add_symbol(&mut self, sym: Sym, value: &LispType) -> Result<(), LispError> {
         use std::collections::hash_map::Entry;
-        match self.local_env.entry(sym.clone()) {
-            Entry::Occupied(_) => {
-                println!("{}:{}","You can't define a symbol twice".red(),sym.green());
-            },
+        match self.local_env.entry(sym) {
+            Entry::Occupied(_) => exception::error_with("You can't define a symbol twice", vec![lisp_atom!(sym, Symbol)]),
             Entry::Vacant(entry) => {
                 entry.insert(value.clone());
+                Ok(())
             }
         }
     }
-    fn set_symbol(&mut self, sym: &String, value: &LispType) {
+    pub(crate) fn set_symbol(&mut self, sym: Sym, value: &LispType) -> Result<(), LispError> {
         use std::collections::hash_map::Entry;
-        match self.local_env.entry(sym.clone()) {
+        match self.local_env.entry(sym) {
             Entry::Occupied(mut entry) => {
                 entry.insert(value.clone());
+                Ok(())
             },
-            Entry::Vacant(_) => {
-                println!("{}","Assigning to a undefined symbol is not allowed".red());
-            }
+            Entry::Vacant(_) => exception::error_with("Assigning to an undefined symbol is not allowed", vec![lisp_atom!(sym, Symbol)]),
         }
     }
-    fn lookup_symbol(&self, s: &String) ->Result<LispType, &'static str>{
-        return if self.local_env.contains_key(s) {
-            Ok(self.local_env[s].clone())
-        } else {
-            match &(self.parent_env) {
-                None => Err("Keyword Error: Undefined symbol"),
-                Some(ptr) => ptr.lookup_symbol(s)
-            }
+    pub(crate) fn lookup_symbol(&self, s: Sym) ->LispResult{
+        match self.local_env.get(&s) {
+            Some(value) => Ok(value.clone()),
+            None => error(format!("{} is not defined!", s))
         }
     }
 }
 
 #[allow(non_snake_case)]
 pub fn Eval(cmd: &str, env: &mut Box<Env>) ->LispType{
-    let v = split_cmd_to_vec(cmd);
-    let expr = cvt_to_nested_expression(&v[..],&mut(0 as usize), 0);
-    eval(&expr, env)
-}
-
-pub fn eval(expr: &LispType, env:&mut Box<Env>) ->LispType {
-    match expr {
-        LispType::Atom(atom) => {
-            match atom {
-                Atomic::Symbol(sym) => {
-                    if let Ok(res) = env.lookup_symbol(sym){
-                        return res;
-                    } else {
-                        println!("{} {}", sym.green(), "is not defined!".red());
-                    }
-                },
-                _ => {  return expr.clone(); }
-            };
-        }
-        LispType::List(list) => {
-            if list.len()==0 {
-                // TODO: deal with empty list
-                // println!("{}","Empty list!".green());
-                return LispType::Atom(Atomic::nil);
-            }
-            let result = eval(&list[0], env);
-            match result {
-                LispType::Atom(Atomic::Symbol(keyword)) => {
-                    match &keyword[..] {
-                        "if" => {
-                            // TODO: can be expanded to
-                            // 1.(if test then...)
-                            // 2.(if test then else...)
-                            if let LispType::Atom(Atomic::t) = eval(&list[1], env) {
-                                return eval(&list[2], env);
-                            } else {
-                                return eval(&list[3], env);
-                            }
-                        },
-                        "define" => {
-                            if let LispType::Atom(Atomic::Symbol(symbol_name)) = &list[1] {
-                                // to avoid borrowing twice
-                                let value = eval(&list[2], env);
-                                env.add_symbol(symbol_name, &value);
-                                // why not working? env_symbol.insert(symbol_name, eval(&list[2], env_symbol, env_op));
-                            } else {
-                                println!("{}","Not a valid symbol name!".red());
-                            }
-                        },
-                        "write" => {
-                            match &list[1] {
-                                LispType::Atom(Atomic::Symbol(s)) => {
-                                    print!("{}", s);
-                                }
-                                _ => {}
-                            }
-                        }
-                        "quote" => {
-                            return list[1].clone();
-                        },
-                        "set!" => {
-                            if let LispType::Atom(Atomic::Symbol(symbol_name)) = &list[1] {
-                                let value = eval(&list[2], env);
-                                env.set_symbol(symbol_name, &value);
-                            } else {
-                                println!("{}","Not a valid symbol name!".red());
-                            }
-                        },
-                        "lambda" => {
-                            let mut param_list: Vec<String> = Vec::new();
-                            if let LispType::List(_list) = &list[1] {
-                                for param in _list{
-                                    if let LispType::Atom(Atomic::Symbol(sym)) = param {
-                                        param_list.push(sym.clone());
-                                    } else {
-                                        println!("{}:{:?}", "Invalid parameters".red(), param);
-                                        return LispType::Atom(Atomic::nil);
-                                    }
-                                }
-                            } else {
-                                println!("{}\n{}", "Syntax Error".red(),"Usage: (lambda (symbol...) expr)".green());
-                                return LispType::Atom(Atomic::nil);
-                            }
-                            return LispType::Atom(Atomic::Proc(Box::new(Proc::new(param_list, list[2].clone(), env.clone()))));
-                        }
-                        _ => {
-                            println!("{}","Undefined keywords!".red());
-                        }
-                    }
-                }
-                LispType::Atom(Atomic::Fun(f)) => {
-                    return f(list[1..].into_iter().map(|subexpr| eval(subexpr, env)).collect());
-                },
-                LispType::Atom(Atomic::Proc(mut uf)) => {
-                    return uf.run(list[1..].into_iter().map(|subexpr| eval(subexpr, env)).collect(), env.clone());
-                }
-                _ => {
-                    return result.clone();
-                }
-            }
+    match eval_source(cmd, env) {
+        Ok(res) => res,
+        Err(err) => {
+            println!("{}", backtrace::report(&err).red());
+            LispType::Atom(Atomic::nil)
         }
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn init_env() ->Env{
-    let mut env = Env::new(None);
-    env.add_symbol(&String::from("PI"), &lisp_atom!(std::f64::consts::PI, Float));
-    env.add_symbol(&String::from("if"), &lisp_atom!(String::from("if"), Symbol));
-    env.add_symbol(&String::from("define"), &lisp_atom!(String::from("define"), Symbol));
-    env.add_symbol(&String::from("quote"), &lisp_atom!(String::from("quote"), Symbol));
-    env.add_symbol(&String::from("set!"), &lisp_atom!(String::from("set!"), Symbol));
-    env.add_symbol(&String::from("lambda"), &lisp_atom!(String::from("lambda"), Symbol));
-    env.add_symbol(&String::from("write"),&lisp_atom!(String::from("write"), Symbol));
-    env.add_symbol(&String::from("+"), &lisp_atom!(add, Fun));
-    env.add_symbol(&String::from("-"), &lisp_atom!(minus, Fun));
-    env.add_symbol(&String::from("*"), &lisp_atom!(mul, Fun));
-    env.add_symbol(&String::from("/"), &lisp_atom!(div, Fun));
-    env.add_symbol(&String::from(">"), &lisp_atom!(gt, Fun));
-    env.add_symbol(&String::from(">="), &lisp_atom!(ge, Fun));
-    env.add_symbol(&String::from("<"), &lisp_atom!(lt, Fun));
-    env.add_symbol(&String::from("<="), &lisp_atom!(le, Fun));
-    env.add_symbol(&String::from("="), &lisp_atom!(eq, Fun));
-    env.add_symbol(&String::from("/="), &lisp_atom!(neq, Fun));
-    env.add_symbol(&String::from("begin"), &lisp_atom!(begin, Fun));
-    env.add_symbol(&String::from("max"), &lisp_atom!(max, Fun));
-    env.add_symbol(&String::from("min"), &lisp_atom!(min, Fun));
-    env.add_symbol(&String::from("abs"), &lisp_atom!(abs, Fun));
-    env.add_symbol(&String::from("append"), &lisp_atom!(append, Fun));
-    env.add_symbol(&String::from("cons"), &lisp_atom!(cons, Fun));
-    env.add_symbol(&String::from("car"), &lisp_atom!(car, Fun));
-    env.add_symbol(&String::from("cdr"), &lisp_atom!(cdr, Fun));
-    env.add_symbol(&String::from("apply"), &lisp_atom!(apply, Fun));
-    env.add_symbol(&String::from("map"), &lisp_atom!(map, Fun));
-    env.add_symbol(&String::from("list"), &lisp_atom!(list_, Fun));
-    env
+/// Evaluates every form of `src` in order and returns the value of the last.
+pub fn eval_source(src: &str, env: &mut Env) ->LispResult {
+    load_source(src, "<input>", env)
 }
 
-/*********************************/
-/**** Below are env functions ****/
-/*********************************/
-
-macro_rules! get_atom_value {
-    ($atom:expr, $ret_type:ty) => {match $atom {
-        LispType::Atom(Atomic::Number(n)) => n as $ret_type,
-        LispType::Atom(Atomic::Float(fp)) => fp as $ret_type,
-        _ => {
-            println!("{}", "Operands should be of type i32 or f64".red());
-            -1 as $ret_type
-        }
-    }};
-}
-
-pub fn add(args: Vec<LispType>) ->LispType {
-    // TODO: recursive
-    let mut res_n = 0;
-    let mut res_fp = 0.0;
-    let mut flag = false;
-    for atom in args.iter(){
-        match atom {
-            LispType::Atom(Atomic::Number(n)) => { res_n+=n; },
-            LispType::Atom(Atomic::Float(fp)) => { res_fp+=fp; flag=true; },
-            _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
-        }
-    }
-    if flag {
-        return lisp_atom!(res_fp+(res_n as f64), Float);
+/// Like `eval_source`, for the text of `source`, e.g. a file, which is where
+/// backtraces say the calls are.
+pub fn load_source(src: &str, source: &str, env: &mut Env) ->LispResult {
+    let mut res = LispType::Atom(Atomic::nil);
+    for expr in read_source(src, source)? {
+        res = eval(&expr, env)?;
     }
-    lisp_atom!(res_n, Number)
+    Ok(res)
 }
 
-pub fn minus(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if args.len()>1 {
-                if let LispType::Atom(Atomic::Float(_fp)) = args[1] {
-                    lisp_atom!(n as f64 - get_atom_value!(args[1],f64), Float)
-                } else {
-                    lisp_atom!(n - get_atom_value!(args[1], i32), Number)
-                }
-            } else {
-                lisp_atom!(-get_atom_value!(args[0],i32), Number)
-            }
-        },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if args.len()>1 {
-                lisp_atom!(fp - get_atom_value!(args[1],f64), Float)
-            } else {
-                lisp_atom!(-get_atom_value!(args[0],f64), Float)
-            }
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+/// Expands, compiles and runs a top-level form. If it fails, the calls it
+/// failed in are kept for `backtrace::report`.
+pub fn eval(expr: &LispType, env: &mut Env) ->LispResult {
+    backtrace::forget();
+    let depth = env.calls.depth();
+    let expanded = env.syntax.borrow_mut().expand(expr);
+    let res = expanded.map_err(LispError::Error)
+        .and_then(|expanded| compile(&expanded))
+        .and_then(|code| match env.engine {
+            Engine::Tree => exec(&code, &None, env),
+            Engine::Vm => vm::run(&code, env)
+        });
+    if res.is_err() {
+        env.calls.failed(depth);
     }
-    LispType::Atom(Atomic::nil)
+    res
 }
 
-pub fn mul(args: Vec<LispType>) ->LispType {
-    let mut res_n = 1;
-    let mut res_fp = 1.0;
-    let mut flag = false;
-    for atom in args.iter(){
-        match atom {
-            LispType::Atom(Atomic::Number(n)) => { res_n*=n; },
-            LispType::Atom(Atomic::Float(fp)) => { res_fp*=fp; flag=true; },
-            _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
-        }
-    }
-    if flag {
-        return lisp_atom!(res_fp*(res_n as f64), Float);
-    }
-    lisp_atom!(res_n, Number)
+/// Result of running an expression in tail position: a call is handed back
+/// to `run_proc` instead of growing the Rust stack.
+enum Tail {
+    Value(LispType),
+    Call(Rc<Proc>, Vec<LispType>, Option<Rc<Span>>)
 }
 
-pub fn div(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return lisp_atom!(n as f64 / get_atom_value!(args[1], f64), Float);
+fn exec(expr: &Expr, frame: &Option<Rc<Frame>>, env: &mut Env) ->LispResult {
+    match expr {
+        Expr::Const(value) => Ok(value.clone()),
+        Expr::Local(depth, idx) => {
+            let frame = Frame::ancestor(frame.as_ref().unwrap(), *depth);
+            let value = frame.slots.borrow()[*idx].clone();
+            Ok(value)
         },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return lisp_atom!(fp / get_atom_value!(args[1],f64), Float);
+        Expr::Global(sym) => env.lookup_symbol(*sym),
+        Expr::SetLocal(depth, idx, value) => {
+            let value = exec(value, frame, env)?;
+            Frame::ancestor(frame.as_ref().unwrap(), *depth).slots.borrow_mut()[*idx] = value;
+            Ok(LispType::Atom(Atomic::nil))
         },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
-    }
-    LispType::Atom(Atomic::nil)
-}
-
-pub fn gt(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if ((n as f64) - get_atom_value!(args[1],f64))>std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
-            }
+        Expr::SetGlobal(sym, value) => {
+            let value = exec(value, frame, env)?;
+            env.set_symbol(*sym, &value)?;
+            Ok(LispType::Atom(Atomic::nil))
+        },
+        Expr::DefineLocal(idx, value) => {
+            let value = exec(value, frame, env)?;
+            frame.as_ref().unwrap().slots.borrow_mut()[*idx] = value;
+            Ok(LispType::Atom(Atomic::nil))
         },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if (fp - get_atom_value!(args[1],f64))>std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
+        Expr::DefineGlobal(sym, value) => {
+            let value = exec(value, frame, env)?;
+            env.add_symbol(*sym, &value)?;
+            Ok(LispType::Atom(Atomic::nil))
+        },
+        Expr::If(test, conseq, alt) => {
+            if let LispType::Atom(Atomic::t) = exec(test, frame, env)? {
+                exec(conseq, frame, env)
             } else {
-                LispType::Atom(Atomic::nil)
+                exec(alt, frame, env)
             }
         },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+        Expr::Lambda(lambda) => Ok(LispType::Atom(Atomic::Proc(Proc::new(lambda.clone(), frame.clone())))),
+        Expr::Call(f, args, at) => {
+            let f = exec(f, frame, env)?;
+            let args = args.iter().map(|arg| exec(arg, frame, env)).collect::<Result<Vec<_>, _>>()?;
+            call_at(f, args, at.clone(), env)
+        },
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn lt(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if ((n as f64) - get_atom_value!(args[1],f64)) < -std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
+fn exec_tail(expr: &Expr, frame: &Option<Rc<Frame>>, env: &mut Env) ->Result<Tail, LispError> {
+    match expr {
+        Expr::If(test, conseq, alt) => {
+            if let LispType::Atom(Atomic::t) = exec(test, frame, env)? {
+                exec_tail(conseq, frame, env)
             } else {
-                LispType::Atom(Atomic::nil)
+                exec_tail(alt, frame, env)
             }
         },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if (fp - get_atom_value!(args[1],f64)) < -std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
+        Expr::Call(f, args, at) => {
+            let f = exec(f, frame, env)?;
+            let args = args.iter().map(|arg| exec(arg, frame, env)).collect::<Result<Vec<_>, _>>()?;
+            match f {
+                LispType::Atom(Atomic::Proc(uf)) => Ok(Tail::Call(uf, args, at.clone())),
+                _ => Ok(Tail::Value(call_at(f, args, at.clone(), env)?))
             }
         },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+        _ => Ok(Tail::Value(exec(expr, frame, env)?))
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn ge(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if ((n as f64) - get_atom_value!(args[1],f64))>= -std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
-            }
-        },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if (fp - get_atom_value!(args[1],f64))>= -std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
-            }
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+/// Calls a procedure value with already evaluated arguments.
+pub fn apply_procedure(f: LispType, args: Vec<LispType>, env: &mut Env) ->LispResult {
+    call_at(f, args, None, env)
+}
+
+/// Like `apply_procedure`, for a call written at `at`. The call is on the
+/// backtrace until it returns, or after it fails until something carries on.
+pub(crate) fn call_at(f: LispType, args: Vec<LispType>, at: Option<Rc<Span>>, env: &mut Env) ->LispResult {
+    if !matches!(f, LispType::Atom(Atomic::Fun(_) | Atomic::Continuation(_) | Atomic::Proc(_))) {
+        return error(format!("{} is not a procedure!", f));
+    }
+    let depth = env.calls.enter(Callee::of(&f), at);
+    let res = match f {
+        LispType::Atom(Atomic::Fun(f)) => f.call(args, env),
+        LispType::Atom(Atomic::Continuation(k)) => continuation::invoke(&k, args, env),
+        LispType::Atom(Atomic::Proc(uf)) if env.engine() == Engine::Vm => vm::call(&uf, args, env),
+        LispType::Atom(Atomic::Proc(uf)) => run_proc(uf, args, depth, env),
+        _ => unreachable!()
+    };
+    if res.is_ok() {
+        env.calls.leave(depth);
     }
-    LispType::Atom(Atomic::nil)
+    res
 }
 
-pub fn le(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if ((n as f64) - get_atom_value!(args[1],f64))<=std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
-            }
-        },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if (fp - get_atom_value!(args[1],f64))<=std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
+/// Runs `uf` on the tree-walker; its tail calls take its place at `depth`
+/// on the backtrace.
+fn run_proc(uf: Rc<Proc>, args: Vec<LispType>, depth: usize, env: &mut Env) ->LispResult {
+    let mut uf = uf;
+    let mut args = args;
+    loop {
+        let frame = Some(uf.bind(args)?);
+        let (last, init) = uf.lambda.body.split_last().unwrap();
+        for expr in init {
+            exec(expr, &frame, env)?;
+        }
+        match exec_tail(last, &frame, env)? {
+            Tail::Value(value) => return Ok(value),
+            Tail::Call(next, next_args, at) => {
+                env.calls.leave(depth);
+                env.calls.enter(Callee::proc(&next), at);
+                uf = next;
+                args = next_args;
             }
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+        }
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn eq(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if ((n as f64) - get_atom_value!(args[1],f64)).abs()<=std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
-            }
-        },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if (fp - get_atom_value!(args[1],f64)).abs()<=std::f64::EPSILON {
-                LispType::Atom(Atomic::t)
-            } else {
-                LispType::Atom(Atomic::nil)
-            }
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+pub fn init_env() ->Env{
+    let mut env = Env::new();
+    env.local_env.insert(Sym::intern("PI"), lisp_atom!(std::f64::consts::PI, Float));
+    env.add_full_builtin("+", add);
+    env.add_full_builtin("-", minus);
+    env.add_full_builtin("*", mul);
+    env.add_full_builtin("/", div);
+    env.add_full_builtin(">", gt);
+    env.add_full_builtin(">=", ge);
+    env.add_full_builtin("<", lt);
+    env.add_full_builtin("<=", le);
+    env.add_full_builtin("=", eq);
+    env.add_full_builtin("/=", neq);
+    env.add_builtin("begin", begin);
+    env.add_full_builtin("max", max);
+    env.add_full_builtin("min", min);
+    env.add_full_builtin("abs", abs);
+    env.add_full_builtin("append", append);
+    env.add_full_builtin("cons", cons);
+    env.add_full_builtin("car", car);
+    env.add_full_builtin("cdr", cdr);
+    env.add_full_builtin("apply", apply);
+    env.add_full_builtin("map", map);
+    env.add_builtin("list", list_);
+    env.add_full_builtin("disassemble", vm::disassemble);
+    env.add_full_builtin("string->symbol", string_to_symbol);
+    env.add_full_builtin("symbol->string", symbol_to_string);
+    env.add_builtin("gensym", gensym);
+    env.add_builtin("gc", gc::gc);
+    env.add_builtin("gc-stats", gc::gc_stats);
+    env.add_builtin("command-line", command_line);
+    env.add_full_builtin("pretty-print", pretty::pretty_print);
+    env.add_full_builtin("display", port::display);
+    env.add_full_builtin("write", port::write);
+    env.add_full_builtin("write-string", port::write_string);
+    env.add_full_builtin("newline", port::newline);
+    env.add_full_builtin("print", port::print);
+    env.add_full_builtin("read-line", port::read_line);
+    env.add_full_builtin("read-char", port::read_char);
+    env.add_full_builtin("read-string", port::read_string);
+    env.add_full_builtin("read", port::read);
+    env.add_full_builtin("open-input-file", port::open_input_file);
+    env.add_full_builtin("open-output-file", port::open_output_file);
+    env.add_full_builtin("open-input-string", port::open_input_string);
+    env.add_full_builtin("open-output-string", port::open_output_string);
+    env.add_full_builtin("get-output-string", port::get_output_string);
+    env.add_full_builtin("close-port", port::close_port);
+    env.add_full_builtin("current-input-port", port::current_input_port);
+    env.add_full_builtin("current-output-port", port::current_output_port);
+    env.add_full_builtin("call-with-output-file", port::call_with_output_file);
+    env.add_full_builtin("with-input-from-file", port::with_input_from_file);
+    env.add_full_builtin("with-output-to-file", port::with_output_to_file);
+    env.add_full_builtin("with-output-to-string", port::with_output_to_string);
+    env.add_full_builtin("format", format::format_builtin);
+    env.add_builtin("eof-object", port::eof_object);
+    env.add_builtin("eof-object?", port::is_eof_object);
+    env.add_full_builtin("raise", exception::raise);
+    env.add_full_builtin("raise-continuable", exception::raise_continuable);
+    env.add_full_builtin("error", exception::error_builtin);
+    env.add_full_builtin("with-exception-handler", exception::with_exception_handler);
+    env.add_full_builtin("%guard", exception::guard);
+    env.add_full_builtin("dynamic-wind", exception::dynamic_wind);
+    env.add_full_builtin("error-object?", exception::is_error_object);
+    env.add_full_builtin("error-object-message", exception::error_object_message);
+    env.add_full_builtin("error-object-irritants", exception::error_object_irritants);
+    env.add_full_builtin("call-with-current-continuation", continuation::call_cc);
+    env.add_full_builtin("call/cc", continuation::call_cc);
+    env.add_full_builtin("backtrace", backtrace::backtrace);
+    eval_source(exception::MACROS, &mut env).expect("the built-in macros are well-formed");
+    env
+}
+
+thread_local! {
+    static COMMAND_LINE: RefCell<Vec<Rc<str>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sets what `(command-line)` returns: the script followed by its arguments.
+pub fn set_command_line(args: Vec<String>) {
+    COMMAND_LINE.with(|cmd| *cmd.borrow_mut() = args.into_iter().map(Rc::from).collect());
+}
+
+pub fn command_line(_args: Vec<LispType>) ->LispType {
+    COMMAND_LINE.with(|cmd| LispType::List(cmd.borrow().iter().map(|arg| lisp_atom!(arg.clone(), Str)).collect()))
+}
+
+/// Usage of the builtins and special forms, as shown by `:doc` in the REPL.
+pub fn doc(name: &str) ->Option<&'static str> {
+    Some(match name {
+        "quote" => "(quote expr) or 'expr\n  expr itself, unevaluated",
+        "if" => "(if cond conseq alt)\n  conseq if cond is t, alt (or nil) otherwise",
+        "define" => "(define sym expr)\n  binds sym to the value of expr",
+        "set!" => "(set! sym expr)\n  assigns the value of expr to the existing binding of sym",
+        "lambda" => "(lambda (params...) expr...)\n  a procedure evaluating the exprs with params bound to its arguments",
+        "define-syntax" => "(define-syntax name (syntax-rules (literals...) (pattern template)...))\n  defines a hygienic macro",
+        "PI" => "PI\n  3.14159...",
+        "+" | "*" => "(op arg1 arg2 ...)\n  sum or product of the arguments",
+        "-" => "(- arg1 arg2) or (- arg)\n  difference, or negation",
+        "/" => "(/ arg1 arg2)\n  quotient as a float",
+        ">" | "<" | ">=" | "<=" | "=" | "/=" => "(cmp arg1 arg2)\n  t if the numbers compare so, nil otherwise",
+        "max" | "min" => "(max arg1 arg2 ...)\n  the largest or smallest argument",
+        "abs" => "(abs arg)\n  absolute value",
+        "begin" => "(begin expr1 ... exprn)\n  the value of the last expression",
+        "append" => "(append list...)\n  the lists joined together",
+        "cons" => "(cons atom list)\n  list with atom in front",
+        "car" => "(car list)\n  first element",
+        "cdr" => "(cdr list)\n  list without its first element",
+        "list" => "(list expr...)\n  a list of the arguments",
+        "apply" => "(apply op atom... list)\n  calls op with the atoms followed by the elements of list",
+        "map" => "(map op list...)\n  list of op applied to the elements of the lists in turn",
+        "disassemble" => "(disassemble procedure)\n  writes the bytecode of a procedure to the current output port",
+        "string->symbol" => "(string->symbol string)\n  the symbol named string",
+        "symbol->string" => "(symbol->string symbol)\n  the name of symbol",
+        "gensym" => "(gensym) or (gensym prefix)\n  a fresh symbol equal to no other",
+        "gc" => "(gc)\n  collects unreachable cycles and returns the heap statistics",
+        "gc-stats" => "(gc-stats)\n  ((frames n) (closures n) (collections n) (freed n))",
+        "command-line" => "(command-line)\n  list of the script name and the arguments passed to it",
+        "display" => "(display expr [port])\n  prints expr for people to read: strings without quotes",
+        "write" => "(write expr [port])\n  prints expr the way read reads it back",
+        "write-string" => "(write-string string [port])\n  prints the characters of string",
+        "newline" => "(newline [port])\n  starts a new line of output",
+        "print" => "(print expr...)\n  displays the values separated by spaces, then a newline",
+        "read-line" => "(read-line [port])\n  the next line of input as a string, or the eof object",
+        "read-char" => "(read-char [port])\n  the next character of input as a string, or the eof object",
+        "read-string" => "(read-string k [port])\n  the next k characters of input, or the eof object",
+        "read" => "(read [port])\n  the next datum of input, or the eof object",
+        "open-input-file" => "(open-input-file path)\n  a port reading the file",
+        "open-output-file" => "(open-output-file path)\n  a port writing the file, which is created or emptied",
+        "open-input-string" => "(open-input-string string)\n  a port reading string",
+        "open-output-string" => "(open-output-string)\n  a port collecting output for get-output-string",
+        "get-output-string" => "(get-output-string port)\n  what was written to a port from open-output-string",
+        "close-port" => "(close-port port)\n  closes port, after which it can't be used",
+        "current-input-port" | "current-output-port" => "(current-output-port)\n  the port read or written when none is given",
+        "call-with-output-file" => "(call-with-output-file path proc)\n  calls proc with a port writing the file and closes it afterwards",
+        "with-input-from-file" => "(with-input-from-file path thunk)\n  calls thunk with the file as the current input port",
+        "with-output-to-file" => "(with-output-to-file path thunk)\n  calls thunk with the file as the current output port",
+        "with-output-to-string" => "(with-output-to-string thunk)\n  what thunk writes to the current output port, as a string",
+        "format" => "(format [destination] control arg...)\n  control with ~a ~s ~d ~f ~% ~~ ~{...~} ~^ filled in from the args;\n  returned as a string, or written to the current output port for t or to a port",
+        "eof-object" => "(eof-object)\n  what reading past the end of input returns",
+        "eof-object?" => "(eof-object? expr)\n  t if expr is the eof object",
+        "raise" => "(raise obj)\n  raises obj, for the nearest guard or with-exception-handler to catch",
+        "raise-continuable" => "(raise-continuable obj)\n  calls the innermost exception handler with obj and returns what it returns",
+        "error" => "(error message irritant...)\n  raises an error object with the message string and the irritants",
+        "with-exception-handler" => "(with-exception-handler handler thunk)\n  calls thunk, and handler with what it raises; returning from handler is an error",
+        "guard" => "(guard (var clause...) body...)\n  the value of body, or if it raises, of the first clause (test expr...)\n  whose test is t with var bound to what was raised; (else expr...) matches anything",
+        "dynamic-wind" => "(dynamic-wind before thunk after)\n  calls before, thunk and after, after even if thunk raises",
+        "error-object?" => "(error-object? obj)\n  t if obj was made by error or a failing builtin",
+        "error-object-message" => "(error-object-message error-object)\n  the message of an error object",
+        "error-object-irritants" => "(error-object-irritants error-object)\n  the irritants of an error object",
+        "call-with-current-continuation" | "call/cc" => "(call/cc proc)\n  calls proc with a procedure that makes call/cc return its argument;\n  the VM engine can also return again after call/cc has returned",
+        "backtrace" => "(backtrace)\n  the calls in progress, innermost first, as (name source line column);\n  in an exception handler, from where the exception was raised",
+        "pretty-print" => "(pretty-print expr [width [port]])\n  prints expr laid out over lines of at most width columns, to port or the current output",
+        _ => return None
+    })
+}
+
+/*********************************/
+/**** Below are env functions ****/
+/*********************************/
+
+/// The value of a numeric argument of `op`.
+fn operand(op: &str, arg: &LispType) ->Result<f64, LispError> {
+    match arg {
+        LispType::Atom(Atomic::Number(n)) => Ok(*n as f64),
+        LispType::Atom(Atomic::Float(fp)) => Ok(*fp),
+        _ => exception::error_with(format!("The operands of '{}' should be of type i32 or f64", op), vec![arg.clone()])
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn neq(args: Vec<LispType>) ->LispType {
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {
-            return if ((n as f64) - get_atom_value!(args[1],f64)).abs()<=std::f64::EPSILON {
-                LispType::Atom(Atomic::nil)
-            } else {
-                LispType::Atom(Atomic::t)
-            }
-        },
-        LispType::Atom(Atomic::Float(fp)) => {
-            return if (fp - get_atom_value!(args[1],f64)).abs()<=std::f64::EPSILON {
-                LispType::Atom(Atomic::nil)
-            } else {
-                LispType::Atom(Atomic::t)
-            }
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+/// Combines `init` with each of `args` in turn, with `int` as long as both
+/// sides are integers and with `float` otherwise.
+fn fold(op: &str, init: &LispType, args: &[LispType], int: fn(i32, i32) ->Option<i32>, float: fn(f64, f64) ->f64) ->LispResult {
+    operand(op, init)?;
+    let mut res = init.clone();
+    for arg in args {
+        res = match (&res, arg) {
+            (LispType::Atom(Atomic::Number(n1)), LispType::Atom(Atomic::Number(n2))) => match int(*n1, *n2) {
+                Some(n) => lisp_atom!(n, Number),
+                None => return exception::error_with(format!("Integer overflow in '{}'", op), vec![res.clone(), arg.clone()])
+            },
+            _ => lisp_atom!(float(operand(op, &res)?, operand(op, arg)?), Float)
+        };
     }
-    LispType::Atom(Atomic::nil)
+    Ok(res)
 }
 
-pub fn begin(args: Vec<LispType>) ->LispType {
-    return args.last().unwrap().clone();
+/*
+(+ arg...)
+ */
+pub fn add(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    fold("+", &lisp_atom!(0, Number), &args, i32::checked_add, |a, b| a + b)
 }
 
-pub fn _max(arg0: &LispType, arg1: &LispType) -> LispType {
-    match (&arg0, &arg1) {
-        (LispType::Atom(Atomic::Number(n1)), LispType::Atom(Atomic::Number(n2))) => {
-            return lisp_atom!(*n1.max(n2), Number);
+/*
+(- arg) or (- arg1 arg2...)
+ */
+pub fn minus(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    match &args[..] {
+        [] => error(String::from("Usage: (- arg1 arg2...) or (- arg)")),
+        [LispType::Atom(Atomic::Number(n))] => match n.checked_neg() {
+            Some(n) => Ok(lisp_atom!(n, Number)),
+            None => exception::error_with("Integer overflow in '-'", args.clone())
         },
-        (LispType::Atom(Atomic::Number(n1)), LispType::Atom(Atomic::Float(n2))) => {
-            return lisp_atom!(n2.max(*n1 as f64), Float);
+        [arg] => Ok(lisp_atom!(-operand("-", arg)?, Float)),
+        [first, rest @ ..] => fold("-", first, rest, i32::checked_sub, |a, b| a - b)
+    }
+}
+
+/*
+(* arg...)
+ */
+pub fn mul(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    fold("*", &lisp_atom!(1, Number), &args, i32::checked_mul, |a, b| a * b)
+}
+
+/*
+(/ arg1 arg2...) or (/ arg), always a float
+ */
+pub fn div(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    let (first, rest) = match &args[..] {
+        [] => return error(String::from("Usage: (/ arg1 arg2...) or (/ arg)")),
+        [_] => (lisp_atom!(1, Number), &args[..1]),
+        [first, rest @ ..] => (first.clone(), rest),
+    };
+    let mut res = operand("/", &first)?;
+    for arg in rest {
+        if let LispType::Atom(Atomic::Number(0)) = arg {
+            return exception::error_with("Division by zero in '/'", vec![first.clone(), arg.clone()]);
         }
-        (LispType::Atom(Atomic::Float(n1)), LispType::Atom(Atomic::Number(n2))) => {
-            return lisp_atom!(n1.max(*n2 as f64), Float);
-        },
-        (LispType::Atom(Atomic::Float(n1)), LispType::Atom(Atomic::Float(n2))) => {
-            return lisp_atom!(n1.max(*n2), Float);
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+        res /= operand("/", arg)?;
     }
-    LispType::Atom(Atomic::nil)
+    Ok(lisp_atom!(res, Float))
 }
 
-pub fn max(args: Vec<LispType>) ->LispType {
-    assert!(args.len()>1);
-    let mut res = args[0].clone();
-    for i in 1..args.len() {
-        res = _max(&res, &args[i]);
+/// Whether `holds` is true of the difference of each two neighbouring
+/// arguments.
+fn compare(op: &str, args: &[LispType], holds: fn(f64) ->bool) ->LispResult {
+    if args.len() < 2 {
+        return error(format!("Usage: ({} arg1 arg2...)", op));
     }
-    res.clone()
+    let mut res = true;
+    for pair in args.windows(2) {
+        res &= holds(operand(op, &pair[0])? - operand(op, &pair[1])?);
+    }
+    Ok(LispType::Atom(if res { Atomic::t } else { Atomic::nil }))
 }
 
-pub fn _min(arg0: &LispType, arg1: &LispType) -> LispType {
-    match (&arg0, &arg1) {
-        (LispType::Atom(Atomic::Number(n1)), LispType::Atom(Atomic::Number(n2))) => {
-            return lisp_atom!(*n1.min(n2), Number);
-        },
-        (LispType::Atom(Atomic::Number(n1)), LispType::Atom(Atomic::Float(n2))) => {
-            return lisp_atom!(n2.min(*n1 as f64), Float);
-        }
-        (LispType::Atom(Atomic::Float(n1)), LispType::Atom(Atomic::Number(n2))) => {
-            return lisp_atom!(n1.min(*n2 as f64), Float);
-        },
-        (LispType::Atom(Atomic::Float(n1)), LispType::Atom(Atomic::Float(n2))) => {
-            return lisp_atom!(n1.min(*n2), Float);
-        },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+pub fn gt(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    compare(">", &args, |d| d > f64::EPSILON)
+}
+
+pub fn lt(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    compare("<", &args, |d| d < -f64::EPSILON)
+}
+
+pub fn ge(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    compare(">=", &args, |d| d >= -f64::EPSILON)
+}
+
+pub fn le(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    compare("<=", &args, |d| d <= f64::EPSILON)
+}
+
+pub fn eq(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    compare("=", &args, |d| d.abs() <= f64::EPSILON)
+}
+
+pub fn neq(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    compare("/=", &args, |d| d.abs() > f64::EPSILON)
+}
+
+pub fn begin(args: Vec<LispType>) ->LispType {
+    args.last().cloned().unwrap_or(LispType::Atom(Atomic::nil))
+}
+
+/*
+(max arg...)
+ */
+pub fn max(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    match args.split_first() {
+        Some((first, rest)) => fold("max", first, rest, |a, b| Some(a.max(b)), f64::max),
+        None => error(String::from("Usage: (max arg1 arg2...)"))
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn min(args: Vec<LispType>) ->LispType {
-    assert!(args.len()>1);
-    let mut res = args[0].clone();
-    for i in 1..args.len() {
-        res = _min(&res, &args[i]);
+/*
+(min arg...)
+ */
+pub fn min(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    match args.split_first() {
+        Some((first, rest)) => fold("min", first, rest, |a, b| Some(a.min(b)), f64::min),
+        None => error(String::from("Usage: (min arg1 arg2...)"))
     }
-    res.clone()
 }
 
-pub fn abs(args: Vec<LispType>) ->LispType{
-    match args[0] {
-        LispType::Atom(Atomic::Number(n)) => {return lisp_atom!(n.abs(), Number); },
-        LispType::Atom(Atomic::Float(n)) => { return lisp_atom!(n.abs(), Float); },
-        _ => { println!("{}", "Operands should be of type i32 or f64".red()); }
+pub fn abs(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    match &args[..] {
+        [LispType::Atom(Atomic::Number(n))] => match n.checked_abs() {
+            Some(n) => Ok(lisp_atom!(n, Number)),
+            None => exception::error_with("Integer overflow in 'abs'", args.clone())
+        },
+        [arg] => Ok(lisp_atom!(operand("abs", arg)?.abs(), Float)),
+        _ => error(String::from("Usage: (abs arg)"))
     }
-    LispType::Atom(Atomic::nil)
 }
 
 /*
 (map Op '<list> '<list> ... '<list>)
  */
-pub fn map(args: Vec<LispType>) ->LispType{
-    let mut res_list: Vec<LispType> = Vec::new();
-    match &args[0] {
-        LispType::Atom(Atomic::Fun(_f)) => {
-            match &args[1] {
-                LispType::List(list) => {
-                    let n = list.len();
-                    let mut v: Vec<Vec<LispType>> = Vec::new();
-                    for i in 0..n {
-                        v.push(vec![list[i].clone()]);
-                    }
-                    for i in 2..args.len() {
-                        match &args[i] {
-                            LispType::List(_list) => {
-                                if _list.len() != n {
-                                    println!("{}", "Arguments except for the 1st should be list of the same size".red());
-                                    return LispType::Atom(Atomic::nil);
-                                }
-                                for (j, elem) in _list.iter().enumerate() {
-                                    v[j].push(elem.clone());
-                                }
-                            }
-                            _ => {
-                                println!("{}", "Arguments except for the 1st should be of type list".red());
-                                return LispType::Atom(Atomic::nil);
-                            }
-                        }
-                    }
-                    for i in 0..n {
-                        res_list.push(_f(v[i].clone()));
-                    }
-                    return LispType::List(res_list);
-                },
-                _ => { println!("{}", "Arguments except for the 1st should be of type list".red()); }
+pub fn map(args: Vec<LispType>, env: &mut Env) ->LispResult {
+    let (f, lists) = match args.split_first() {
+        Some((f, lists)) if !lists.is_empty() => (f, lists),
+        _ => return error(String::from("Usage: (map op list...)"))
+    };
+    // the arguments of each call
+    let mut calls: Vec<Vec<LispType>> = Vec::new();
+    for (i, arg) in lists.iter().enumerate() {
+        let list = match arg {
+            LispType::List(list) => list,
+            _ => return error(String::from("Arguments except for the 1st should be of type list"))
+        };
+        if i == 0 {
+            calls = list.iter().map(|elem| vec![elem.clone()]).collect();
+        } else if list.len() != calls.len() {
+            return error(String::from("Arguments except for the 1st should be list of the same size"));
+        } else {
+            for (call, elem) in calls.iter_mut().zip(list) {
+                call.push(elem.clone());
             }
-        },
-        _ => { println!("{}", "The first argument of 'map' should be a function".red()); }
+        }
     }
-    LispType::Atom(Atomic::nil)
+    let res = calls.into_iter().map(|args| apply_procedure(f.clone(), args, env)).collect::<Result<Vec<_>, _>>()?;
+    Ok(LispType::List(res.into()))
 }
 
 /*
 (apply Op '<List>)
 (apply Op <Atom> <Atom> ... '<List>)
  */
-pub fn apply(args: Vec<LispType>) ->LispType{
-    match &args[0] {
-        LispType::Atom(Atomic::Fun(_f)) => {
-            let mut expanded_args = (&args[1..args.len()-1]).to_owned();
-            match args.last().unwrap() {
-                LispType::List(list) => {
-                    for elem in list {
-                        expanded_args.push(elem.clone());
-                    }
-                    return _f(expanded_args);
-                }
-                _ => { println!("{}","The last argument of 'apply' should be a list".red()); }
-            }
-        }
-        _ => { println!("{}","The first argument of 'apply' should be a function".red()); }
-    }
-    LispType::Atom(Atomic::nil)
+pub fn apply(args: Vec<LispType>, env: &mut Env) ->LispResult {
+    let (f, init, last) = match &args[..] {
+        [f, init @ .., last] => (f, init, last),
+        _ => return error(String::from("Usage: (apply op atom... list)"))
+    };
+    let list = match last {
+        LispType::List(list) => list,
+        _ => return error(String::from("The last argument of 'apply' should be a list"))
+    };
+    let mut expanded_args = init.to_vec();
+    expanded_args.extend(list.iter().cloned());
+    apply_procedure(f.clone(), expanded_args, env)
 }
 
-pub fn car(args: Vec<LispType>) ->LispType{
-    match &args[0] {
-        LispType::List(_list) => {
-            return _list[0].clone();
-        }
-        _ => {}
+/// The only argument of a list function, which should be a list.
+fn list_arg<'a>(name: &str, args: &'a [LispType]) ->Result<&'a [LispType], LispError> {
+    match args {
+        [LispType::List(list)] => Ok(list),
+        [LispType::Atom(Atomic::nil)] => Ok(&[]),
+        [arg] => exception::error_with(format!("The argument of '{}' should be a list", name), vec![arg.clone()]),
+        _ => error(format!("Usage: ({} list)", name))
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn cdr(args: Vec<LispType>) ->LispType{
+/*
+(car list), nil for an empty list
+ */
+pub fn car(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    Ok(list_arg("car", &args)?.first().cloned().unwrap_or(LispType::Atom(Atomic::nil)))
+}
+
+/*
+(cdr list), nil for an empty list
+ */
+pub fn cdr(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    list_arg("cdr", &args)?;
     match &args[0] {
-        LispType::List(_list) => {
-            return LispType::List(_list[1..].to_owned());
-        }
-        _ => {}
+        LispType::List(list) if !list.is_empty() => Ok(LispType::List(list.tail())),
+        _ => Ok(LispType::Atom(Atomic::nil))
     }
-    LispType::Atom(Atomic::nil)
 }
 
-pub fn append(args: Vec<LispType>) ->LispType{
+pub fn append(args: Vec<LispType>, _env: &mut Env) ->LispResult {
     let mut n_list: Vec<LispType> = Vec::new();
     for arg in args.iter() {
-        if let LispType::List(_list) = arg {
-            n_list.append(&mut _list.clone());
-        } else {
-            println!("{}","Arguments should be of type List".red());
+        match arg {
+            LispType::List(list) => n_list.extend_from_slice(list),
+            LispType::Atom(Atomic::nil) => {},
+            _ => return exception::error_with("The arguments of 'append' should be lists", vec![arg.clone()])
         }
     }
-    LispType::List(n_list)
+    Ok(LispType::List(n_list.into()))
 }
 
-
-pub fn cons(args: Vec<LispType>) ->LispType{
-    // TODO empty list
-    match &args[1] {
-        LispType::Atom(atom) => {
-            return if let Atomic::nil = atom {
-                LispType::List(vec![args[0].clone()])
-            } else {
-                LispType::List(vec![args[0].clone(),args[1].clone()])
-            }
-        },
-        LispType::List(list) => {
-            let mut retv = vec![args[0].clone()];
-            for e in list.iter(){
-                retv.push(e.clone());
-            }
-            return LispType::List(retv);
-        }
+/*
+(cons expr list)
+ */
+pub fn cons(args: Vec<LispType>, _env: &mut Env) ->LispResult {
+    // TODO dotted pairs
+    match &args[..] {
+        [head, LispType::Atom(Atomic::nil)] => Ok(LispType::List(vec![head.clone()].into())),
+        [head, LispType::List(list)] => Ok(LispType::List(std::iter::once(head.clone()).chain(list.iter().cloned()).collect())),
+        [head, tail] => Ok(LispType::List(vec![head.clone(), tail.clone()].into())),
+        _ => error(String::from("Usage: (cons expr list)"))
     }
 }
 
 pub fn list_(args: Vec<LispType>) ->LispType{
-    return LispType::List(args);
+    LispType::List(args.into())
+}
+
+pub fn string_to_symbol(args: Vec<LispType>, _env: &mut Env) ->LispResult{
+    match args.as_slice() {
+        [LispType::Atom(Atomic::Str(s))] => Ok(lisp_atom!(Sym::intern(s), Symbol)),
+        _ => exception::error_with("The argument of 'string->symbol' should be a string", args),
+    }
+}
+
+pub fn symbol_to_string(args: Vec<LispType>, _env: &mut Env) ->LispResult{
+    match args.as_slice() {
+        [LispType::Atom(Atomic::Symbol(sym))] => Ok(lisp_atom!(sym.name(), Str)),
+        _ => exception::error_with("The argument of 'symbol->string' should be a symbol", args),
+    }
+}
+
+/*
+(gensym) or (gensym "prefix")
+ */
+pub fn gensym(args: Vec<LispType>) ->LispType{
+    match args.first() {
+        Some(LispType::Atom(Atomic::Str(prefix))) => lisp_atom!(Sym::gensym(prefix), Symbol),
+        _ => lisp_atom!(Sym::gensym("g"), Symbol)
+    }
 }
 
 /*********************************/
@@ -775,9 +1228,21 @@ pub fn list_(args: Vec<LispType>) ->LispType{
 /*********************************/
 
 #[cfg(test)]
+#[allow(clippy::approx_constant, clippy::legacy_numeric_constants)]
 mod tests {
     use super::*;
 
+    macro_rules! get_atom_value {
+        ($atom:expr, $ret_type:ty) => {match $atom {
+            LispType::Atom(Atomic::Number(n)) => n as $ret_type,
+            LispType::Atom(Atomic::Float(fp)) => fp as $ret_type,
+            _ => {
+                println!("{}", "Operands should be of type i32 or f64".red());
+                -1 as $ret_type
+            }
+        }};
+    }
+
     #[test]
     fn test_add() {
         let mut env = Box::new(init_env());
@@ -877,4 +1342,65 @@ mod tests {
         assert_eq!(format!("{}", Eval("(cons 3.14 '(10 1.2))",&mut env)), "(3.14 10 1.2)");
         assert_eq!(format!("{}", Eval("(cons 3.14 (cons E (cons 1 nil)))",&mut env)), "(3.14 2.7 1)");
     }
+
+    #[test]
+    fn test_fibo() {
+        let mut env = Box::new(init_env());
+        Eval("(define fibo (lambda (x) (if (< x 2) x (+ (fibo (- x 1)) (fibo (- x 2))))))", &mut env);
+        assert_eq!(Eval("(fibo 20)", &mut env), lisp_atom!(6765, Number));
+    }
+
+    #[test]
+    fn test_closure() {
+        let mut env = Box::new(init_env());
+        Eval("(define make-counter (lambda () (define n 0) (lambda () (set! n (+ n 1)) n)))", &mut env);
+        Eval("(define c1 (make-counter))", &mut env);
+        Eval("(define c2 (make-counter))", &mut env);
+        Eval("(c1)", &mut env);
+        Eval("(c1)", &mut env);
+        assert_eq!(Eval("(c1)", &mut env), lisp_atom!(3, Number));
+        assert_eq!(Eval("(c2)", &mut env), lisp_atom!(1, Number));
+        // free variables are resolved where the lambda was written, not where it is called
+        Eval("(define x 1)", &mut env);
+        Eval("(define get-x (lambda () x))", &mut env);
+        assert_eq!(Eval("((lambda (x) (get-x)) 2)", &mut env), lisp_atom!(1, Number));
+    }
+
+    #[test]
+    fn test_internal_define_and_tail_call() {
+        let mut env = Box::new(init_env());
+        Eval("(define parity (lambda (n) (define ev? (lambda (n) (if (= n 0) t (od? (- n 1))))) (define od? (lambda (n) (if (= n 0) nil (ev? (- n 1))))) (ev? n)))", &mut env);
+        assert_eq!(Eval("(parity 100000)", &mut env), LispType::Atom(Atomic::t));
+        assert_eq!(Eval("(parity 7)", &mut env), LispType::Atom(Atomic::nil));
+        assert_eq!(Eval("((lambda (x y) x) 1)", &mut env), LispType::Atom(Atomic::nil));
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(split_cmd_to_vec("(a ; note (b\n c)"), vec!["(", "a", "c", ")"]);
+        assert_eq!(split_cmd_to_vec("(a;x\nb \"; not a comment\")"), vec!["(", "a", "b", "\"; not a comment\"", ")"]);
+        assert_eq!(split_cmd_to_vec("(a #| outer #| inner |# still outer |# b)"), vec!["(", "a", "b", ")"]);
+        assert_eq!(split_cmd_to_vec("(a #;(b (c)) d)"), vec!["(", "a", "d", ")"]);
+        assert_eq!(split_cmd_to_vec("(a #; #;b c d)"), vec!["(", "a", "d", ")"]);
+        assert_eq!(split_cmd_to_vec("'#;a b"), vec!["(", "quote", "b", ")"]);
+        assert_eq!(split_cmd_to_vec("#;'a b"), vec!["b"]);
+        assert_eq!(split_cmd_to_vec("(a #;)"), vec!["(", "a", ")"]);
+        let mut env = Box::new(init_env());
+        assert_eq!(Eval("(+ 1 #| 2 |# 3) ; sum", &mut env), lisp_atom!(4, Number));
+        assert_eq!(Eval("; only a comment", &mut env), LispType::Atom(Atomic::nil));
+    }
+
+    #[test]
+    fn test_incomplete_input() {
+        for src in ["(define f", "(a (b)", "'", "(a '", "\"abc", "(a \"b)\"", "#| (a) ", "#| #| |#", "#;", "(a #;"] {
+            assert!(is_incomplete(src), "{:?} should be incomplete", src);
+        }
+        for src in ["", "a", "(a)", "(a))", "'a", "\"a\\\"\"", "; (", "#| ( |#", "#;a", "(a ; )\n)"] {
+            assert!(!is_incomplete(src), "{:?} should be complete", src);
+        }
+        let mut env = Box::new(init_env());
+        assert!(eval_source("(define f (lambda (x)", &mut env).is_err());
+        Eval("(define sq\n  (lambda (x)\n    (* x x)))", &mut env);
+        assert_eq!(Eval("(sq 7)", &mut env), lisp_atom!(49, Number));
+    }
 }
@@ -1,36 +1,119 @@
+use std::io::{IsTerminal, Read};
+use std::process::exit;
 use colored::Colorize;
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
-use crate::lisparse::{Eval};
+use rlisp::backtrace;
+use rlisp::formatter::format_source;
+use rlisp::lisparse;
+use rlisp::lisparse::Engine;
+use crate::cli::{Action, FmtOptions};
 
-mod lisparse;
+mod cli;
+mod repl;
 
 fn main() {
-    println!("{}\n{}","This is a Lisp interpreter with Rust runtime.".green(),"Press C-c to exit.".green());
-    let mut _buf = Ok(String::new());
+    let opts = match cli::parse(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, cli::USAGE);
+            exit(2);
+        }
+    };
+    if opts.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if !opts.color || !std::io::stdout().is_terminal() {
+        colored::control::set_override(false);
+    }
+    if let Some(fmt_opts) = &opts.fmt {
+        exit(fmt(fmt_opts));
+    }
     let mut env = Box::new(lisparse::init_env());
-    let mut rl = Editor::<()>::new();
-    if rl.load_history("input_history.txt").is_err(){}
-    loop {
-        _buf = rl.readline(&">> ".green());
-        match _buf {
-            Ok(line) => {
-                rl.add_history_entry(line.as_str());
-                println!("{}",Eval(&line, &mut env));
-            },
-            Err(ReadlineError::Interrupted) => {
-                println!("C-c");
-                break
-            },
-            Err(ReadlineError::Eof) => {
-                println!("C-d");
-                break
-            },
+    if std::env::var("RLISP_ENGINE").map(|engine| engine == "vm").unwrap_or(false) {
+        env.set_engine(Engine::Vm);
+    }
+    let starts_repl = opts.starts_repl(std::io::stdin().is_terminal());
+    lisparse::set_command_line(opts.command_line);
+    // the init file sets up the session, so the actions before the REPL see it too
+    if starts_repl {
+        repl::load_init_file(&mut env);
+    }
+    for action in &opts.actions {
+        let res = match action {
+            Action::Eval(expr) => repl::run_expr(expr, &mut env, &mut std::io::stdout()),
+            Action::Load(path) => match std::fs::read_to_string(path) {
+                Ok(src) => lisparse::load_source(&src, &path.display().to_string(), &mut env).map(|_| ()),
+                Err(err) => {
+                    eprintln!("{}", format!("Can't read {}: {}", path.display(), err).red());
+                    exit(2);
+                }
+            }
+        };
+        if let Err(err) = res {
+            eprintln!("{}", backtrace::report(&err).red());
+            exit(1);
+        }
+    }
+    if !starts_repl && !opts.actions.is_empty() {
+        return;
+    }
+    if !starts_repl {
+        let mut src = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut src) {
+            eprintln!("Can't read stdin: {}", err);
+            exit(2);
+        }
+        let ok = repl::run_filter(&src, &mut env, &mut std::io::stdout());
+        exit(if ok { 0 } else { 1 });
+    }
+    if !opts.quiet {
+        println!("{}\n{}","This is a Lisp interpreter with Rust runtime.".green(),"Press C-c to exit.".green());
+    }
+    let history = opts.history.or_else(repl::history_path);
+    repl::run(&mut env, history.as_deref());
+}
+
+/// Runs `rlisp fmt` and returns the exit status.
+fn fmt(opts: &FmtOptions) -> i32 {
+    if opts.files.is_empty() {
+        let mut src = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut src) {
+            eprintln!("Can't read stdin: {}", err);
+            return 2;
+        }
+        return match format_source(&src, opts.width) {
+            Ok(out) if opts.check => (out != src) as i32,
+            Ok(out) => {
+                print!("{}", out);
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err.to_string().red());
+                2
+            }
+        };
+    }
+    let mut status = 0;
+    for path in &opts.files {
+        let res = std::fs::read_to_string(path).map_err(|err| err.to_string())
+            .and_then(|src| format_source(&src, opts.width).map(|out| (src, out)).map_err(|err| err.to_string()));
+        match res {
+            Ok((src, out)) if out == src => {}
+            Ok(_) if opts.check => {
+                println!("{}", path.display());
+                status = status.max(1);
+            }
+            Ok((_, out)) => {
+                if let Err(err) = std::fs::write(path, out) {
+                    eprintln!("{}", format!("Can't write {}: {}", path.display(), err).red());
+                    status = 2;
+                }
+            }
             Err(err) => {
-                println!("Error: {:?}", err);
-                break
+                eprintln!("{}", format!("{}: {}", path.display(), err).red());
+                status = 2;
             }
         }
     }
-    rl.save_history("input_history.txt").unwrap();
+    status
 }
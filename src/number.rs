@@ -0,0 +1,169 @@
+use crate::lisparse::{Atomic, LispError};
+
+/*
+Numeric literals:
+  [#x|#o|#b|#d] [+|-] digits               integer in the given radix
+  [#x|#o|#b|#d] [+|-] digits / digits      rational
+  [+|-] digits [. digits] [e [+|-] digits] decimal, `1.` and `.5` included
+  +inf.0 -inf.0 +nan.0 -nan.0
+
+There is no exact rational type, so a rational that isn't a whole number
+reads as the nearest float.
+ */
+
+/// Parses `token` as a number. `None` means it isn't numeric syntax at all
+/// and should be read as a symbol; an error means it is numeric syntax but
+/// can't be represented.
+pub fn parse_number(token: &str) -> Option<Result<Atomic, LispError>> {
+    let (radix, body) = match token.as_bytes() {
+        [b'#', prefix, ..] => (match prefix.to_ascii_lowercase() {
+            b'x' => 16,
+            b'o' => 8,
+            b'b' => 2,
+            b'd' => 10,
+            _ => return None,
+        }, &token[2..]),
+        _ => (10, token),
+    };
+    let (negative, digits) = match body.as_bytes().first() {
+        Some(b'+') => (false, &body[1..]),
+        Some(b'-') => (true, &body[1..]),
+        _ => (false, body),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    if radix == 10 && digits.len() < body.len() {
+        match digits.to_ascii_lowercase().as_str() {
+            "inf.0" => return Some(Ok(Atomic::Float(if negative { f64::NEG_INFINITY } else { f64::INFINITY }))),
+            "nan.0" => return Some(Ok(Atomic::Float(f64::NAN))),
+            _ => {}
+        }
+    }
+    if let Some((num, den)) = digits.split_once('/') {
+        let num = parse_digits(num, radix)?;
+        let den = parse_digits(den, radix)?;
+        return Some(rational(token, if negative { -num } else { num }, den));
+    }
+    if let Some(n) = parse_digits(digits, radix) {
+        let n = if negative { -n } else { n };
+        return Some(i32::try_from(n).map(Atomic::Number).map_err(|_| out_of_range(token)));
+    }
+    if radix == 10 && is_decimal(digits) {
+        return body.parse::<f64>().ok().map(|fp| Ok(Atomic::Float(fp)));
+    }
+    None
+}
+
+/// Digits of `radix`, saturating at i128::MAX; literals that big are out of
+/// range anyway.
+fn parse_digits(digits: &str, radix: u32) -> Option<i128> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    if digits.len() > 38 {
+        return Some(i128::MAX);
+    }
+    Some(i128::from_str_radix(digits, radix).unwrap_or(i128::MAX))
+}
+
+fn rational(token: &str, num: i128, den: i128) -> Result<Atomic, LispError> {
+    if den == 0 {
+        return Err(LispError::Error(format!("Division by zero in number literal {}", token)));
+    }
+    if num % den == 0 {
+        return i32::try_from(num / den).map(Atomic::Number).map_err(|_| out_of_range(token));
+    }
+    Ok(Atomic::Float(num as f64 / den as f64))
+}
+
+fn out_of_range(token: &str) -> LispError {
+    LispError::Error(format!("Integer literal {} is out of range", token))
+}
+
+/// digits [. digits] [e [+|-] digits], with at least one mantissa digit
+fn is_decimal(s: &str) -> bool {
+    let (mantissa, exponent) = match s.find(['e', 'E']) {
+        Some(idx) => (&s[..idx], Some(&s[idx+1..])),
+        None => (s, None),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if int.len() + frac.len() == 0 || !all_digits(int) || !all_digits(frac) {
+        return false;
+    }
+    match exponent {
+        None => true,
+        Some(exp) => {
+            let exp = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+            !exp.is_empty() && all_digits(exp)
+        }
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::*;
+
+    fn number(token: &str) -> Atomic {
+        match parse_number(token) {
+            Some(Ok(atom)) => atom,
+            other => panic!("{} didn't parse: {:?}", token, other),
+        }
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(number("42"), Atomic::Number(42));
+        assert_eq!(number("+5"), Atomic::Number(5));
+        assert_eq!(number("0"), Atomic::Number(0));
+        assert_eq!(number("#x000"), Atomic::Number(0));
+        assert_eq!(number("-17"), Atomic::Number(-17));
+        assert_eq!(number("#x1F"), Atomic::Number(31));
+        assert_eq!(number("#X-ff"), Atomic::Number(-255));
+        assert_eq!(number("#b1010"), Atomic::Number(10));
+        assert_eq!(number("#o17"), Atomic::Number(15));
+        assert_eq!(number("#d99"), Atomic::Number(99));
+        assert_eq!(number("6/3"), Atomic::Number(2));
+        assert_eq!(number("-2147483648"), Atomic::Number(i32::MIN));
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(number("1e10"), Atomic::Float(1e10));
+        assert_eq!(number("1."), Atomic::Float(1.0));
+        assert_eq!(number(".5"), Atomic::Float(0.5));
+        assert_eq!(number("-2.5E-3"), Atomic::Float(-0.0025));
+        assert_eq!(number("1/4"), Atomic::Float(0.25));
+        assert_eq!(number("+inf.0"), Atomic::Float(f64::INFINITY));
+        assert_eq!(number("-inf.0"), Atomic::Float(f64::NEG_INFINITY));
+        assert!(matches!(number("-nan.0"), Atomic::Float(fp) if fp.is_nan()));
+    }
+
+    #[test]
+    fn test_not_numbers() {
+        for token in ["+", "-", "...", "1+", "e10", ".", "1e", "#x", "#x1.5", "inf.0", "1/", "#t", "a1", "1..2"] {
+            assert!(parse_number(token).is_none(), "{} parsed as a number", token);
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert!(matches!(parse_number("2147483648"), Some(Err(_))));
+        assert!(matches!(parse_number("99999999999999999999999999999999999999999999"), Some(Err(_))));
+        assert!(matches!(parse_number("#xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"), Some(Err(_))));
+        assert!(matches!(parse_number("1/0"), Some(Err(_))));
+        let mut env = Box::new(init_env());
+        assert_eq!(Eval("(+ 1 99999999999)", &mut env), LispType::Atom(Atomic::nil));
+        assert_eq!(Eval("(+ #x10 1e1 +5)", &mut env), LispType::Atom(Atomic::Float(31.0)));
+    }
+}
@@ -0,0 +1,572 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::rc::Rc;
+use crate::formatter::datum_len;
+use crate::lisparse::{apply_procedure, error, is_incomplete, read_all, Atomic, Env, LispError, LispResult, LispType};
+use crate::printer;
+
+/*
+Ports are where input comes from and where output goes: stdin and stdout,
+files, or strings. They are values, and the I/O builtins take one as their
+last, optional argument. Without it they use the current input or output
+port, stdin and stdout unless redirected with with-input-from-file and the
+like (`with_input` and `with_output` here).
+
+An input port reads a line at a time and keeps what hasn't been consumed,
+so read-char, read-line and read can be mixed on the same port.
+
+Every I/O failure, a file that can't be opened as well as a closed port,
+is an error rather than a message.
+ */
+
+pub struct Port {
+    inner: RefCell<Inner>,
+}
+
+enum Inner {
+    Input { reader: Box<dyn BufRead>, buf: String },
+    Output(Box<dyn Write>),
+    /// output kept in memory
+    StringOutput(String),
+    Closed,
+}
+
+impl std::fmt::Debug for Port {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#<{}>", self.kind())
+    }
+}
+
+fn io_error(err: std::io::Error) -> LispError {
+    LispError::Error(format!("I/O error: {}", err))
+}
+
+fn open_error(path: &str, err: std::io::Error) -> LispError {
+    LispError::Error(format!("Can't open {}: {}", path, err))
+}
+
+impl Port {
+    pub fn stdin() -> Rc<Port> {
+        Port::input(Box::new(BufReader::new(std::io::stdin())))
+    }
+    pub fn stdout() -> Rc<Port> {
+        Rc::new(Port { inner: RefCell::new(Inner::Output(Box::new(std::io::stdout()))) })
+    }
+    pub fn input(reader: Box<dyn BufRead>) -> Rc<Port> {
+        Rc::new(Port { inner: RefCell::new(Inner::Input { reader, buf: String::new() }) })
+    }
+    /// An input port reading `s`.
+    pub fn input_string(s: &str) -> Rc<Port> {
+        Port::input(Box::new(Cursor::new(s.to_string().into_bytes())))
+    }
+    /// An output port collecting what is written to it, see `output`.
+    pub fn output_string() -> Rc<Port> {
+        Rc::new(Port { inner: RefCell::new(Inner::StringOutput(String::new())) })
+    }
+
+    pub fn open_input_file(path: &str) -> Result<Rc<Port>, LispError> {
+        let file = File::open(path).map_err(|err| open_error(path, err))?;
+        Ok(Port::input(Box::new(BufReader::new(file))))
+    }
+    pub fn open_output_file(path: &str) -> Result<Rc<Port>, LispError> {
+        let file = File::create(path).map_err(|err| open_error(path, err))?;
+        Ok(Rc::new(Port { inner: RefCell::new(Inner::Output(Box::new(file))) }))
+    }
+
+    /// What the printer calls it.
+    pub fn kind(&self) -> &'static str {
+        match &*self.inner.borrow() {
+            Inner::Input { .. } => "input port",
+            Inner::Output(_) | Inner::StringOutput(_) => "output port",
+            Inner::Closed => "closed port",
+        }
+    }
+
+    /// What has been written to a string output port.
+    pub fn output(&self) -> Option<String> {
+        match &*self.inner.borrow() {
+            Inner::StringOutput(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// Closes the port, flushing what was written to it. Closing it again
+    /// does nothing.
+    pub fn close(&self) -> Result<(), LispError> {
+        match std::mem::replace(&mut *self.inner.borrow_mut(), Inner::Closed) {
+            Inner::Output(mut out) => out.flush().map_err(io_error),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn write_str(&self, s: &str) -> Result<(), LispError> {
+        match &mut *self.inner.borrow_mut() {
+            Inner::Output(out) => out.write_all(s.as_bytes()).and_then(|_| out.flush()).map_err(io_error),
+            Inner::StringOutput(out) => {
+                out.push_str(s);
+                Ok(())
+            }
+            Inner::Input { .. } => error(String::from("Can't write to an input port")),
+            Inner::Closed => error(String::from("Can't write to a closed port")),
+        }
+    }
+
+    /// Calls `f` with the unread input until it returns a value, reading
+    /// another line whenever it returns `None`. `f` is told when there is
+    /// nothing more to read, and then has to return a value.
+    fn consume<T>(&self, mut f: impl FnMut(&mut String, bool) -> Result<Option<T>, LispError>) -> Result<T, LispError> {
+        let mut inner = self.inner.borrow_mut();
+        let (reader, buf) = match &mut *inner {
+            Inner::Input { reader, buf } => (reader, buf),
+            Inner::Closed => return error(String::from("Can't read from a closed port")),
+            _ => return error(String::from("Can't read from an output port")),
+        };
+        let mut eof = false;
+        loop {
+            match f(buf, eof) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {
+                    assert!(!eof, "nothing was read at the end of input");
+                    // a prompt written without a newline has to show up first
+                    let _ = std::io::stdout().flush();
+                    eof = reader.read_line(buf).map_err(io_error)? == 0;
+                }
+                Err(err) => {
+                    buf.clear();
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// The next character, or `None` at the end of the input.
+    pub fn read_char(&self) -> Result<Option<char>, LispError> {
+        self.consume(|buf, eof| Ok(match buf.chars().next() {
+            Some(c) => {
+                buf.drain(..c.len_utf8());
+                Some(Some(c))
+            }
+            None if eof => Some(None),
+            None => None,
+        }))
+    }
+
+    /// The rest of the current line without the line break, or `None` at
+    /// the end of the input.
+    pub fn read_line(&self) -> Result<Option<String>, LispError> {
+        self.consume(|buf, eof| Ok(match buf.find('\n') {
+            Some(i) => {
+                let line: String = buf.drain(..i + 1).collect();
+                Some(Some(line[..i].trim_end_matches('\r').to_string()))
+            }
+            None if eof && buf.is_empty() => Some(None),
+            None if eof => Some(Some(std::mem::take(buf))),
+            None => None,
+        }))
+    }
+
+    /// The next `k` characters, fewer at the end of the input, or `None`
+    /// if there are none left.
+    pub fn read_string(&self, k: usize) -> Result<Option<String>, LispError> {
+        self.consume(|buf, eof| {
+            let end = buf.char_indices().nth(k).map(|(i, _)| i);
+            Ok(match end {
+                Some(end) => Some(Some(buf.drain(..end).collect())),
+                None if eof && buf.is_empty() && k > 0 => Some(None),
+                None if eof || k == 0 => Some(Some(std::mem::take(buf))),
+                None => None,
+            })
+        })
+    }
+
+    /// The next datum, or `Eof` at the end of the input.
+    pub fn read(&self) -> LispResult {
+        self.consume(|buf, eof| {
+            if !is_incomplete(buf) {
+                match datum_len(buf)? {
+                    Some(len) => {
+                        let text: String = buf.drain(..len).collect();
+                        return Ok(read_all(&text)?.into_iter().next());
+                    }
+                    None => buf.clear(),
+                }
+            }
+            match eof {
+                true if buf.trim().is_empty() => Ok(Some(LispType::Atom(Atomic::Eof))),
+                true => error(String::from("Unexpected end of input")),
+                false => Ok(None),
+            }
+        })
+    }
+}
+
+thread_local! {
+    static INPUT: RefCell<Rc<Port>> = RefCell::new(Port::stdin());
+    static OUTPUT: RefCell<Rc<Port>> = RefCell::new(Port::stdout());
+}
+
+pub fn current_input() -> Rc<Port> {
+    INPUT.with(|port| port.borrow().clone())
+}
+
+pub fn current_output() -> Rc<Port> {
+    OUTPUT.with(|port| port.borrow().clone())
+}
+
+/// Runs `f` with `port` as the current input port.
+pub fn with_input<R>(port: Rc<Port>, f: impl FnOnce() -> R) -> R {
+    let saved = INPUT.with(|current| current.replace(port));
+    let res = f();
+    INPUT.with(|current| current.replace(saved));
+    res
+}
+
+/// Runs `f` with `port` as the current output port.
+pub fn with_output<R>(port: Rc<Port>, f: impl FnOnce() -> R) -> R {
+    let saved = OUTPUT.with(|current| current.replace(port));
+    let res = f();
+    OUTPUT.with(|current| current.replace(saved));
+    res
+}
+
+fn nil() -> LispResult {
+    Ok(LispType::Atom(Atomic::nil))
+}
+
+fn str_value(s: String) -> LispType {
+    LispType::Atom(Atomic::Str(s.into()))
+}
+
+/// Fails with the usage unless there are `min` to `max` arguments.
+fn arity(args: &[LispType], min: usize, max: usize, usage: &str) -> Result<(), LispError> {
+    match args.len() {
+        n if n < min || n > max => error(format!("Usage: {}", usage)),
+        _ => Ok(()),
+    }
+}
+
+/// The port argument at `i`, or `default` if there isn't one.
+pub(crate) fn port_arg(args: &[LispType], i: usize, default: fn() -> Rc<Port>) -> Result<Rc<Port>, LispError> {
+    match args.get(i) {
+        None => Ok(default()),
+        Some(LispType::Atom(Atomic::Port(port))) => Ok(port.clone()),
+        Some(other) => error(format!("{} is not a port", other)),
+    }
+}
+
+fn string_arg(args: &[LispType], i: usize) -> Result<Rc<str>, LispError> {
+    match args.get(i) {
+        Some(LispType::Atom(Atomic::Str(s))) => Ok(s.clone()),
+        Some(other) => error(format!("{} is not a string", other)),
+        None => error(String::from("Missing string argument")),
+    }
+}
+
+fn print_with(args: Vec<LispType>, usage: &str, to_string: fn(&LispType) -> String) -> LispResult {
+    arity(&args, 1, 2, usage)?;
+    port_arg(&args, 1, current_output)?.write_str(&to_string(&args[0]))?;
+    nil()
+}
+
+/*
+(display expr) or (display expr port)
+ */
+pub fn display(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    print_with(args, "(display expr [port])", printer::display_string)
+}
+
+/*
+(write expr) or (write expr port)
+ */
+pub fn write(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    print_with(args, "(write expr [port])", printer::write_string)
+}
+
+/*
+(write-string string) or (write-string string port)
+ */
+pub fn write_string(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 2, "(write-string string [port])")?;
+    port_arg(&args, 1, current_output)?.write_str(&string_arg(&args, 0)?)?;
+    nil()
+}
+
+/*
+(newline) or (newline port)
+ */
+pub fn newline(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 1, "(newline [port])")?;
+    port_arg(&args, 0, current_output)?.write_str("\n")?;
+    nil()
+}
+
+/*
+(print expr...) displays the values separated by spaces, then a newline
+ */
+pub fn print(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    let line: Vec<String> = args.iter().map(printer::display_string).collect();
+    current_output().write_str(&format!("{}\n", line.join(" ")))?;
+    nil()
+}
+
+fn eof_or(value: Option<String>) -> LispType {
+    value.map_or(LispType::Atom(Atomic::Eof), str_value)
+}
+
+/*
+(read-line) or (read-line port)
+ */
+pub fn read_line(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 1, "(read-line [port])")?;
+    Ok(eof_or(port_arg(&args, 0, current_input)?.read_line()?))
+}
+
+/*
+(read-char) or (read-char port) returns the character as a string of length 1
+ */
+pub fn read_char(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 1, "(read-char [port])")?;
+    Ok(eof_or(port_arg(&args, 0, current_input)?.read_char()?.map(String::from)))
+}
+
+/*
+(read-string k) or (read-string k port)
+ */
+pub fn read_string(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 2, "(read-string k [port])")?;
+    let k = match &args[0] {
+        LispType::Atom(Atomic::Number(k)) if *k >= 0 => *k as usize,
+        other => return error(format!("{} is not a character count", other)),
+    };
+    Ok(eof_or(port_arg(&args, 1, current_input)?.read_string(k)?))
+}
+
+/*
+(read) or (read port)
+ */
+pub fn read(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 1, "(read [port])")?;
+    port_arg(&args, 0, current_input)?.read()
+}
+
+/*
+(eof-object)
+ */
+pub fn eof_object(_args: Vec<LispType>) -> LispType {
+    LispType::Atom(Atomic::Eof)
+}
+
+/*
+(eof-object? expr)
+ */
+pub fn is_eof_object(args: Vec<LispType>) -> LispType {
+    match args.first() {
+        Some(LispType::Atom(Atomic::Eof)) => LispType::Atom(Atomic::t),
+        _ => LispType::Atom(Atomic::nil),
+    }
+}
+
+fn port_value(port: Rc<Port>) -> LispType {
+    LispType::Atom(Atomic::Port(port))
+}
+
+/*
+(open-input-file path)
+ */
+pub fn open_input_file(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 1, "(open-input-file path)")?;
+    Ok(port_value(Port::open_input_file(&string_arg(&args, 0)?)?))
+}
+
+/*
+(open-output-file path) creates the file, or empties it
+ */
+pub fn open_output_file(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 1, "(open-output-file path)")?;
+    Ok(port_value(Port::open_output_file(&string_arg(&args, 0)?)?))
+}
+
+/*
+(open-input-string string)
+ */
+pub fn open_input_string(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 1, "(open-input-string string)")?;
+    Ok(port_value(Port::input_string(&string_arg(&args, 0)?)))
+}
+
+/*
+(open-output-string)
+ */
+pub fn open_output_string(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 0, "(open-output-string)")?;
+    Ok(port_value(Port::output_string()))
+}
+
+/*
+(get-output-string port)
+ */
+pub fn get_output_string(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 1, "(get-output-string port)")?;
+    match port_arg(&args, 0, current_output)?.output() {
+        Some(s) => Ok(str_value(s)),
+        None => error(format!("{} is not a string output port", args[0])),
+    }
+}
+
+/*
+(close-port port)
+ */
+pub fn close_port(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 1, 1, "(close-port port)")?;
+    port_arg(&args, 0, current_output)?.close()?;
+    nil()
+}
+
+/*
+(current-input-port)
+ */
+pub fn current_input_port(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 0, "(current-input-port)")?;
+    Ok(port_value(current_input()))
+}
+
+/*
+(current-output-port)
+ */
+pub fn current_output_port(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    arity(&args, 0, 0, "(current-output-port)")?;
+    Ok(port_value(current_output()))
+}
+
+/// Calls `f`, then closes `port` whether `f` failed or not.
+fn closing(port: &Port, f: impl FnOnce() -> LispResult) -> LispResult {
+    let res = f();
+    port.close()?;
+    res
+}
+
+/*
+(call-with-output-file path proc) calls proc with a port writing to the
+file, and closes it when proc returns
+ */
+pub fn call_with_output_file(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 2, 2, "(call-with-output-file path proc)")?;
+    let port = Port::open_output_file(&string_arg(&args, 0)?)?;
+    closing(&port, || apply_procedure(args[1].clone(), vec![port_value(port.clone())], env))
+}
+
+/*
+(with-input-from-file path thunk) calls thunk reading from the file
+ */
+pub fn with_input_from_file(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 2, 2, "(with-input-from-file path thunk)")?;
+    let port = Port::open_input_file(&string_arg(&args, 0)?)?;
+    closing(&port, || with_input(port.clone(), || apply_procedure(args[1].clone(), vec![], env)))
+}
+
+/*
+(with-output-to-file path thunk) calls thunk writing to the file
+ */
+pub fn with_output_to_file(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 2, 2, "(with-output-to-file path thunk)")?;
+    let port = Port::open_output_file(&string_arg(&args, 0)?)?;
+    closing(&port, || with_output(port.clone(), || apply_procedure(args[1].clone(), vec![], env)))
+}
+
+/*
+(with-output-to-string thunk) calls thunk and returns what it wrote
+ */
+pub fn with_output_to_string(args: Vec<LispType>, env: &mut Env) -> LispResult {
+    arity(&args, 1, 1, "(with-output-to-string thunk)")?;
+    let port = Port::output_string();
+    with_output(port.clone(), || apply_procedure(args[0].clone(), vec![], env))?;
+    Ok(str_value(port.output().unwrap_or_default()))
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::*;
+
+    fn output_of(src: &str, env: &mut Box<Env>) -> String {
+        let port = Port::output_string();
+        with_output(port.clone(), || Eval(src, env));
+        port.output().unwrap()
+    }
+
+    #[test]
+    fn test_output() {
+        let mut env = Box::new(init_env());
+        assert_eq!(output_of("(display \"a\\\"b\")", &mut env), "a\"b");
+        assert_eq!(output_of("(write \"a\\\"b\")", &mut env), "\"a\\\"b\"");
+        assert_eq!(output_of("(begin (write (list 'x 2.0)) (newline))", &mut env), "(x 2.0)\n");
+        assert_eq!(output_of("(print \"sum:\" (+ 1 2) 'ok)", &mut env), "sum: 3 ok\n");
+        Eval("(define greet (lambda (name) (display \"hi \") (display name)))", &mut env);
+        assert_eq!(output_of("(greet 'bob)", &mut env), "hi bob");
+        env.set_engine(Engine::Vm);
+        assert_eq!(output_of("(greet \"vm\")", &mut env), "hi vm");
+    }
+
+    #[test]
+    fn test_input() {
+        let mut env = Box::new(init_env());
+        let input = Port::input_string("ab\n(+ 1\n 2) ; sum\n#;skipped 'x\nline\n");
+        with_input(input, || {
+            assert_eq!(Eval("(read-char)", &mut env).to_string(), "\"a\"");
+            assert_eq!(Eval("(read-line)", &mut env).to_string(), "\"b\"");
+            assert_eq!(Eval("(read)", &mut env).to_string(), "(+ 1 2)");
+            assert_eq!(Eval("(read)", &mut env).to_string(), "(quote x)");
+            assert_eq!(Eval("(read-line)", &mut env).to_string(), "\"\"");
+            assert_eq!(Eval("(read-line)", &mut env).to_string(), "\"line\"");
+            assert_eq!(Eval("(eof-object? (read))", &mut env), LispType::Atom(Atomic::t));
+            assert_eq!(Eval("(read-line)", &mut env), LispType::Atom(Atomic::Eof));
+        });
+        with_input(Port::input_string("(unfinished"), || {
+            assert_eq!(Eval("(read)", &mut env), LispType::Atom(Atomic::nil));
+        });
+    }
+
+    #[test]
+    fn test_string_ports() {
+        let mut env = Box::new(init_env());
+        Eval("(define in (open-input-string \"hello world\nbye\"))", &mut env);
+        assert_eq!(Eval("(read-string 5 in)", &mut env).to_string(), "\"hello\"");
+        assert_eq!(Eval("(read in)", &mut env).to_string(), "world");
+        assert_eq!(Eval("(read-line in)", &mut env).to_string(), "\"\"");
+        assert_eq!(Eval("(read-string 10 in)", &mut env).to_string(), "\"bye\"");
+        assert_eq!(Eval("(read-char in)", &mut env), LispType::Atom(Atomic::Eof));
+        Eval("(define out (open-output-string))", &mut env);
+        Eval("(write-string \"a\" out)", &mut env);
+        Eval("(write 'b out)", &mut env);
+        Eval("(newline out)", &mut env);
+        assert_eq!(Eval("(get-output-string out)", &mut env).to_string(), "\"ab\\n\"");
+        assert_eq!(Eval("out", &mut env).to_string(), "#<output port>");
+        assert_eq!(Eval("(with-output-to-string (lambda () (display 1) (display (current-output-port))))", &mut env).to_string(),
+                   "\"1#<output port>\"");
+        Eval("(close-port in)", &mut env);
+        assert_eq!(Eval("in", &mut env).to_string(), "#<closed port>");
+        assert!(eval_source("(read-line in)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_files() {
+        let path = std::env::temp_dir().join(format!("rlisp-port-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut env = Box::new(init_env());
+        Eval(&format!("(define path {:?})", path), &mut env);
+        Eval("(call-with-output-file path (lambda (port) (write '(1 \"two\") port) (newline port)))", &mut env);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "(1 \"two\")\n");
+        assert_eq!(Eval("(with-input-from-file path read)", &mut env).to_string(), "(1 \"two\")");
+        Eval("(with-output-to-file path (lambda () (print 'replaced)))", &mut env);
+        assert_eq!(Eval("(read-line (open-input-file path))", &mut env).to_string(), "\"replaced\"");
+        std::fs::remove_file(path).unwrap();
+        let err = eval_source("(open-input-file path)", &mut env).unwrap_err();
+        assert!(err.to_string().starts_with("Can't open"), "{}", err);
+        assert!(eval_source("(display 1 'not-a-port)", &mut env).is_err());
+    }
+}
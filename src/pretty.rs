@@ -0,0 +1,302 @@
+use std::cell::Cell;
+use crate::lisparse::{error, Atomic, Env, LispResult, LispType};
+use crate::port::{current_output, port_arg};
+use crate::printer;
+
+/*
+A pretty-printer in the style of Wadler's "A prettier printer". Values are
+turned into a `Doc`, which `render` lays out within a width: a group is
+printed on one line if it fits in what's left of the line, and otherwise
+every line break directly in it becomes a newline.
+
+Lists are laid out by what they start with:
+  (define name          forms with a body keep their first few arguments
+    body...)            on the first line and indent the body by 2
+  (if cond              other calls align their arguments with the first
+      conseq
+      alt)
+  (1 2                  lists that don't start with a symbol align their
+   3)                   elements
+  (quote x)             is printed as 'x
+ */
+
+const DEFAULT_WIDTH: usize = 80;
+
+thread_local! {
+    static WIDTH: Cell<usize> = const { Cell::new(DEFAULT_WIDTH) };
+}
+
+/// The width `pretty-print` and the REPL lay values out in.
+pub fn width() -> usize {
+    WIDTH.with(Cell::get)
+}
+
+pub fn set_width(width: usize) {
+    WIDTH.with(|w| w.set(width));
+}
+
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Text(String),
+    /// a space, or a newline if the enclosing group is broken
+    Line,
+    /// a newline, which breaks every group around it
+    HardLine,
+    /// nothing, but the enclosing groups can't be flat
+    BreakParent,
+    Concat(Vec<Doc>),
+    /// newlines inside are indented `n` more than the enclosing ones
+    Nest(usize, Box<Doc>),
+    /// newlines inside are indented to the column it starts at
+    Align(Box<Doc>),
+    /// printed flat if it fits, broken otherwise
+    Group(Box<Doc>),
+}
+
+impl Doc {
+    pub fn text(s: impl Into<String>) -> Doc {
+        Doc::Text(s.into())
+    }
+    pub fn nest(n: usize, doc: Doc) -> Doc {
+        Doc::Nest(n, Box::new(doc))
+    }
+    pub fn align(doc: Doc) -> Doc {
+        Doc::Align(Box::new(doc))
+    }
+    pub fn group(doc: Doc) -> Doc {
+        Doc::Group(Box::new(doc))
+    }
+}
+
+/// `docs` with a `Line` between each two.
+fn lines(docs: Vec<Doc>) -> Doc {
+    let mut out = Vec::with_capacity(docs.len() * 2);
+    for doc in docs {
+        if !out.is_empty() {
+            out.push(Doc::Line);
+        }
+        out.push(doc);
+    }
+    Doc::Concat(out)
+}
+
+/// How many arguments of a form with a body stay on the first line, or
+/// `None` for forms without a body.
+fn body_indent_args(head: &str) -> Option<usize> {
+    match head {
+        "begin" => Some(0),
+        "define" | "define-syntax" | "lambda" | "let" | "let*" | "letrec" | "letrec*" | "syntax-rules" | "when" | "unless"
+        | "guard" => Some(1),
+        _ => None,
+    }
+}
+
+/// How many items `list` keeps on the first line followed by a space
+/// rather than a line break.
+pub fn inline_items(head: Option<&str>) -> usize {
+    head.map_or(0, |head| 1 + body_indent_args(head).unwrap_or(0))
+}
+
+/// The layout of a list of the already laid out `items`. `head` is the
+/// name of the first item if it is a symbol.
+pub fn list(head: Option<&str>, items: Vec<Doc>) -> Doc {
+    let mut items = items.into_iter();
+    let first = match items.next() {
+        Some(first) => first,
+        None => return Doc::text("()"),
+    };
+    let rest: Vec<Doc> = items.collect();
+    let open = Doc::text("(");
+    let close = Doc::text(")");
+    let doc = match head {
+        _ if rest.is_empty() => Doc::Concat(vec![open, first, close]),
+        Some(head) => match body_indent_args(head) {
+            Some(n) => {
+                let n = n.min(rest.len());
+                let mut line = vec![open, first];
+                let mut rest = rest.into_iter();
+                for arg in rest.by_ref().take(n) {
+                    line.push(Doc::text(" "));
+                    line.push(arg);
+                }
+                let body: Vec<Doc> = rest.flat_map(|form| [Doc::Line, form]).collect();
+                line.push(Doc::nest(2, Doc::Concat(body)));
+                line.push(close);
+                Doc::Concat(line)
+            }
+            None => Doc::Concat(vec![open, first, Doc::text(" "), Doc::align(lines(rest)), close]),
+        },
+        None => Doc::Concat(vec![open, Doc::align(lines(std::iter::once(first).chain(rest).collect())), close]),
+    };
+    Doc::group(doc)
+}
+
+/// The layout of a value.
+pub fn doc(expr: &LispType) -> Doc {
+    match expr {
+        LispType::List(items) => match &items[..] {
+            [LispType::Atom(Atomic::Symbol(quote)), datum] if &*quote.name() == "quote" =>
+                Doc::Concat(vec![Doc::text("'"), doc(datum)]),
+            [LispType::Atom(Atomic::Symbol(head)), ..] => list(Some(&head.name()), items.iter().map(doc).collect()),
+            _ => list(None, items.iter().map(doc).collect()),
+        },
+        LispType::Atom(_) => Doc::Text(printer::write_string(expr)),
+    }
+}
+
+/// Lays `doc` out in `width` columns, or as few more as it can't be helped.
+pub fn render(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    // (indentation, flat, doc), the next one to print on top
+    let mut stack: Vec<(usize, bool, &Doc)> = vec![(0, false, doc)];
+    while let Some((indent, flat, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                out.push_str(s);
+                column += s.chars().count();
+            }
+            Doc::Line if flat => {
+                out.push(' ');
+                column += 1;
+            }
+            Doc::Line | Doc::HardLine => {
+                // an empty line would otherwise keep the indentation
+                while out.ends_with(' ') {
+                    out.pop();
+                }
+                out.push('\n');
+                out.extend(std::iter::repeat_n(' ', indent));
+                column = indent;
+            }
+            Doc::BreakParent => {}
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, flat, doc))),
+            Doc::Nest(n, doc) => stack.push((indent + n, flat, doc)),
+            Doc::Align(doc) => stack.push((column, flat, doc)),
+            Doc::Group(doc) => {
+                let flat = flat || fits(width as isize - column as isize, doc, &stack);
+                stack.push((indent, flat, doc));
+            }
+        }
+    }
+    out
+}
+
+/// Whether `doc` printed flat, and what follows it up to the next newline,
+/// fit in `room` columns.
+fn fits(mut room: isize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut stack: Vec<(bool, &Doc)> = vec![(true, doc)];
+    let mut rest = rest.iter().rev();
+    while room >= 0 {
+        let (flat, doc) = match stack.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(&(_, flat, doc)) => (flat, doc),
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(s) => room -= s.chars().count() as isize,
+            Doc::Line if flat => room -= 1,
+            Doc::HardLine | Doc::BreakParent if flat => return false,
+            Doc::Line | Doc::HardLine => return true,
+            Doc::BreakParent => {}
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (flat, doc))),
+            Doc::Nest(_, doc) | Doc::Align(doc) | Doc::Group(doc) => stack.push((flat, doc)),
+        }
+    }
+    false
+}
+
+/// `expr` laid out in `width` columns.
+pub fn pretty(expr: &LispType, width: usize) -> String {
+    render(&doc(expr), width)
+}
+
+/*
+(pretty-print expr), (pretty-print expr width) or (pretty-print expr width port)
+ */
+pub fn pretty_print(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    if args.is_empty() || args.len() > 3 {
+        return error(String::from("Usage: (pretty-print expr [width [port]])"));
+    }
+    let width = match args.get(1) {
+        None => width(),
+        Some(LispType::Atom(Atomic::Number(n))) if *n > 0 => *n as usize,
+        Some(_) => return error(String::from("The width of 'pretty-print' should be a positive integer")),
+    };
+    let port = port_arg(&args, 2, current_output)?;
+    port.write_str(&pretty(&args[0], width))?;
+    port.write_str("\n")?;
+    Ok(LispType::Atom(Atomic::nil))
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::*;
+
+    fn layout(src: &str, width: usize) -> String {
+        pretty(&read_all(src).unwrap()[0], width)
+    }
+
+    #[test]
+    fn test_fits_on_one_line() {
+        assert_eq!(layout("(define sq (lambda (x) (* x x)))", 80), "(define sq (lambda (x) (* x x)))");
+        assert_eq!(layout("(quote (a (quote b) \"c\" 2.0))", 80), "'(a 'b \"c\" 2.0)");
+        assert_eq!(layout("()", 1), "()");
+    }
+
+    #[test]
+    fn test_indentation_rules() {
+        assert_eq!(layout("(define sq (lambda (x) (* x x)))", 24), "\
+(define sq
+  (lambda (x) (* x x)))");
+        assert_eq!(layout("(define fibo (lambda (x) (if (< x 2) x (+ (fibo (- x 1)) (fibo (- x 2))))))", 40), "\
+(define fibo
+  (lambda (x)
+    (if (< x 2)
+        x
+        (+ (fibo (- x 1))
+           (fibo (- x 2))))))");
+        assert_eq!(layout("(let ((a 1) (b 2)) (display a) (+ a b))", 16), "\
+(let ((a 1)
+      (b 2))
+  (display a)
+  (+ a b))");
+        assert_eq!(layout("(1 22 333 4444 (5 6))", 10), "\
+(1
+ 22
+ 333
+ 4444
+ (5 6))");
+    }
+
+    #[test]
+    fn test_reads_back() {
+        let src = "(define make (lambda (n) (list 'deep (list 1.5 \"s\" (quote (x y)) (lambda (a b) (+ a b n))))))";
+        for width in [1, 10, 30, 80] {
+            let printed = layout(src, width);
+            assert_eq!(read_all(&printed).unwrap(), read_all(src).unwrap(), "{}", printed);
+        }
+    }
+
+    #[test]
+    fn test_pretty_print() {
+        let mut env = Box::new(init_env());
+        assert_eq!(Eval("(pretty-print '(a b) 10)", &mut env), LispType::Atom(Atomic::nil));
+        Eval("(define out (open-output-string))", &mut env);
+        Eval("(pretty-print '(define sq (lambda (x) (* x x))) 24 out)", &mut env);
+        assert_eq!(Eval("(get-output-string out)", &mut env).to_string(), "\"(define sq\\n  (lambda (x) (* x x)))\\n\"");
+        for src in ["(pretty-print)", "(pretty-print 1 0)", "(pretty-print 1 10 'x)", "(pretty-print 1 10 out 2)"] {
+            assert!(eval_source(src, &mut env).is_err(), "{} didn't fail", src);
+        }
+        set_width(40);
+        assert_eq!(width(), 40);
+        set_width(DEFAULT_WIDTH);
+    }
+}
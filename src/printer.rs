@@ -0,0 +1,203 @@
+use std::fmt::Write;
+use crate::lisparse::{Atomic, LispType};
+use crate::number::parse_number;
+
+/*
+Values are printed in one of two styles:
+  write    reads back as an equal value: strings are quoted and escaped,
+           floats always look like floats, symbols that would read as
+           something else are written between bars: |a b|  |12|  ||
+  display  for people: strings are printed as their contents
+
+Values with no written syntax print as #<...>, which the reader rejects
+instead of turning into something else:
+  #<procedure name>  #<procedure>  for an anonymous lambda
+  #<builtin name>
+  #<input port>  #<output port>  #<closed port>
+  #<eof>
+  #<continuation>
+  #<error "message" irritant...>
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Write,
+    Display,
+}
+
+/// `expr` as `write` prints it.
+pub fn write_string(expr: &LispType) -> String {
+    to_string(expr, Style::Write)
+}
+
+/// `expr` as `display` prints it.
+pub fn display_string(expr: &LispType) -> String {
+    to_string(expr, Style::Display)
+}
+
+pub fn to_string(expr: &LispType, style: Style) -> String {
+    let mut out = String::new();
+    print(&mut out, expr, style);
+    out
+}
+
+fn print(out: &mut String, expr: &LispType, style: Style) {
+    match expr {
+        LispType::Atom(atom) => print_atom(out, atom, style),
+        LispType::List(list) => {
+            out.push('(');
+            for (i, elem) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                print(out, elem, style);
+            }
+            out.push(')');
+        }
+    }
+}
+
+pub fn atom_to_string(atom: &Atomic, style: Style) -> String {
+    let mut out = String::new();
+    print_atom(&mut out, atom, style);
+    out
+}
+
+fn print_atom(out: &mut String, atom: &Atomic, style: Style) {
+    match atom {
+        Atomic::Number(n) => { let _ = write!(out, "{}", n); },
+        Atomic::Float(fp) => out.push_str(&float_to_string(*fp)),
+        Atomic::Symbol(sym) if style == Style::Write && needs_bars(&sym.name()) => {
+            out.push('|');
+            for c in sym.name().chars() {
+                if c == '|' || c == '\\' {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out.push('|');
+        },
+        Atomic::Symbol(sym) => out.push_str(&sym.name()),
+        Atomic::Str(s) if style == Style::Display => out.push_str(s),
+        Atomic::Str(s) => {
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\t' => out.push_str("\\t"),
+                    _ => out.push(c)
+                }
+            }
+            out.push('"');
+        },
+        Atomic::nil => out.push_str("nil"),
+        Atomic::t => out.push('t'),
+        Atomic::Fun(builtin) => { let _ = write!(out, "#<builtin {}>", builtin.name); },
+        Atomic::Proc(uf) => match uf.lambda.name {
+            Some(name) => { let _ = write!(out, "#<procedure {}>", name); },
+            None => out.push_str("#<procedure>"),
+        },
+        Atomic::Special(form) => out.push_str(form.name()),
+        Atomic::Eof => out.push_str("#<eof>"),
+        Atomic::Port(port) => { let _ = write!(out, "#<{}>", port.kind()); },
+        Atomic::Continuation(_) => out.push_str("#<continuation>"),
+        Atomic::Condition(condition) => {
+            out.push_str("#<error ");
+            print_atom(out, &Atomic::Str(condition.message.clone()), Style::Write);
+            for irritant in condition.irritants.iter() {
+                out.push(' ');
+                print(out, irritant, Style::Write);
+            }
+            out.push('>');
+        },
+    }
+}
+
+/// Whether a symbol named `name` would read back as something else.
+fn needs_bars(name: &str) -> bool {
+    name.is_empty()
+        || name == "nil"
+        || name == "t"
+        || name.starts_with(['#', '\'', '"', '|'])
+        || name.contains(|c: char| c.is_whitespace() || "()\";|\\".contains(c))
+        || parse_number(name).is_some()
+}
+
+/// The shortest text that reads back as `fp`, and never as an integer:
+/// `2.0`, `0.1`, `1e21`, `+inf.0`, `+nan.0`.
+pub fn float_to_string(fp: f64) -> String {
+    if fp.is_nan() {
+        String::from("+nan.0")
+    } else if fp.is_infinite() {
+        String::from(if fp > 0.0 { "+inf.0" } else { "-inf.0" })
+    } else {
+        // Debug keeps the `.0` of whole numbers and switches to an exponent
+        // for very large and small ones, both of which the reader takes as floats
+        format!("{:?}", fp)
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::*;
+    use crate::symbol::Sym;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(float_to_string(2.0), "2.0");
+        assert_eq!(float_to_string(-0.5), "-0.5");
+        assert_eq!(float_to_string(1e21), "1e21");
+        assert_eq!(float_to_string(1.5e-7), "1.5e-7");
+        assert_eq!(float_to_string(f64::NEG_INFINITY), "-inf.0");
+        assert_eq!(float_to_string(f64::NAN), "+nan.0");
+    }
+
+    #[test]
+    fn test_write_and_display() {
+        let mut env = Box::new(init_env());
+        let value = Eval("(list \"a \\\"b\\\"\" 'c 2.0 (list 1 -3.25))", &mut env);
+        assert_eq!(write_string(&value), "(\"a \\\"b\\\"\" c 2.0 (1 -3.25))");
+        assert_eq!(display_string(&value), "(a \"b\" c 2.0 (1 -3.25))");
+        assert_eq!(Eval("+", &mut env).to_string(), "#<builtin +>");
+        assert_eq!(Eval("string->symbol", &mut env).to_string(), "#<builtin string->symbol>");
+        Eval("(define sq (lambda (x) (* x x)))", &mut env);
+        assert_eq!(Eval("sq", &mut env).to_string(), "#<procedure sq>");
+        assert_eq!(Eval("(lambda (x) x)", &mut env).to_string(), "#<procedure>");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut env = Box::new(init_env());
+        for src in ["(1 2.0 -0.0 \"x\\ny\" (sym 1e21) nil t)", "(+inf.0 -inf.0 0.1 123456789.125)"] {
+            let value = Eval(&format!("'{}", src), &mut env);
+            let written = write_string(&value);
+            assert_eq!(read_all(&written).unwrap(), vec![value], "{} didn't read back", written);
+        }
+        let nan = Eval("+nan.0", &mut env);
+        assert!(matches!(read_all(&write_string(&nan)).unwrap()[..], [LispType::Atom(Atomic::Float(fp))] if fp.is_nan()));
+        assert!(read_all(&Eval("car", &mut env).to_string()).is_err());
+    }
+
+    #[test]
+    fn test_symbols_round_trip() {
+        let mut env = Box::new(init_env());
+        for name in ["a b", "12", "-1.5", "", "nil", "t", "(x)", "a|b", "back\\slash", "'q", "#<eof>", "x;y", "plain"] {
+            let value = LispType::Atom(Atomic::Symbol(Sym::intern(name)));
+            let written = write_string(&value);
+            assert_eq!(read_all(&written).unwrap(), vec![value.clone()], "{} didn't read back", written);
+            assert_eq!(display_string(&value), name);
+        }
+        assert_eq!(write_string(&Eval("(string->symbol \"a b\")", &mut env)), "|a b|");
+        assert_eq!(write_string(&Eval("'plain", &mut env)), "plain");
+        assert_eq!(Eval("(symbol->string '|hello world|)", &mut env), LispType::Atom(Atomic::Str(Rc::from("hello world"))));
+        assert_eq!(read_all("|abc|").unwrap(), read_all("abc").unwrap());
+    }
+}
@@ -0,0 +1,586 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use colored::Colorize;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::History;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use rlisp::backtrace;
+use rlisp::lisparse::{self, Atomic, Env, Eval, LispError, LispType};
+use rlisp::number::parse_number;
+use rlisp::pretty;
+
+/// What the REPL does after a meta-command.
+#[derive(Debug, PartialEq)]
+pub enum Control {
+    Continue,
+    Quit,
+}
+
+const COMMANDS: [(&str, &str); 9] = [
+    (":help", "this list"),
+    (":env", "the global bindings"),
+    (":doc sym", "documentation of a builtin, special form or procedure"),
+    (":load file", "evaluates every form in file"),
+    (":time expr", "evaluates expr and reports how long it took"),
+    (":reset", "starts over with a fresh global environment and the init file"),
+    (":history", "the input history"),
+    (":width [n]", "the width results are laid out in, or sets it"),
+    (":quit", "exits"),
+];
+
+/// `$RLISP_HISTORY`, or `rlisp/history` in the XDG data directory.
+pub fn history_path() -> Option<PathBuf> {
+    history_path_from(|var| std::env::var_os(var))
+}
+
+fn history_path_from(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    if let Some(path) = var("RLISP_HISTORY") {
+        return (!path.is_empty()).then(|| PathBuf::from(path));
+    }
+    // the spec says to ignore a relative XDG_DATA_HOME
+    let data = var("XDG_DATA_HOME").map(PathBuf::from).filter(|dir| dir.is_absolute())
+        .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+    Some(data.join("rlisp").join("history"))
+}
+
+/// `$RLISP_INIT`, or `~/.rlisprc`. An empty `RLISP_INIT` disables it.
+pub fn init_file() -> Option<PathBuf> {
+    init_file_from(|var| std::env::var_os(var))
+}
+
+fn init_file_from(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    match var("RLISP_INIT") {
+        Some(path) => (!path.is_empty()).then(|| PathBuf::from(path)),
+        None => var("HOME").map(|home| PathBuf::from(home).join(".rlisprc")),
+    }
+}
+
+fn warn(msg: String) {
+    eprintln!("{}", format!("Warning: {}", msg).yellow());
+}
+
+/// Evaluates the init file, if there is one, into `env`.
+pub fn load_init_file(env: &mut Env) {
+    let path = match init_file() {
+        Some(path) => path,
+        None => return,
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(src) => {
+            if let Err(err) = lisparse::load_source(&src, &path.display().to_string(), env) {
+                warn(format!("{}: {}", path.display(), backtrace::report(&err)));
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => warn(format!("can't read {}: {}", path.display(), err)),
+    }
+}
+
+fn load_history(rl: &mut Editor<LispHelper>, path: Option<&Path>) {
+    if let Some(path) = path {
+        // a missing file just means there is no history yet
+        let _ = rl.load_history(path);
+    }
+}
+
+/// Saves the history, creating its directory first, and warns if it can't.
+fn save_history(rl: &mut Editor<LispHelper>, path: Option<&Path>) {
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+    let saved = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir).map_err(|err| err.to_string()),
+        _ => Ok(()),
+    }.and_then(|_| rl.save_history(path).map_err(|err| err.to_string()));
+    if let Err(err) = saved {
+        warn(format!("can't save history to {}: {}", path.display(), err));
+    }
+}
+
+/// Whether a value is worth printing: nil is what definitions, `set!` and
+/// the output procedures return.
+fn has_value(value: &LispType) -> bool {
+    !matches!(value, LispType::Atom(Atomic::nil))
+}
+
+/// Evaluates the forms of `-e EXPR` and writes the value of the last one to
+/// `out`, unless it is nil.
+pub fn run_expr(expr: &str, env: &mut Env, out: &mut dyn std::io::Write) -> Result<(), LispError> {
+    let forms = lisparse::read_all(expr)?;
+    let mut value = LispType::Atom(Atomic::nil);
+    for form in &forms {
+        value = lisparse::eval(form, env)?;
+    }
+    if has_value(&value) {
+        let _ = writeln!(out, "{}", value);
+    }
+    Ok(())
+}
+
+/// Evaluates every form of `src` without prompts, for piped input. Values
+/// other than nil are written to `out`, errors go to stderr
+/// and the remaining forms still run. Returns whether everything succeeded.
+pub fn run_filter(src: &str, env: &mut Env, out: &mut dyn std::io::Write) -> bool {
+    let forms = match lisparse::read_all(src) {
+        Ok(forms) => forms,
+        Err(err) => {
+            eprintln!("{}", err.to_string().red());
+            return false;
+        }
+    };
+    let mut ok = true;
+    for form in forms {
+        match lisparse::eval(&form, env) {
+            Ok(value) if !has_value(&value) => {}
+            Ok(value) => {
+                let _ = writeln!(out, "{}", value);
+            }
+            Err(err) => {
+                eprintln!("{}", backtrace::report(&err).red());
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Runs the read-eval-print loop until C-c, C-d or `:quit`. History is
+/// loaded from and saved to `history`.
+pub fn run(env: &mut Box<Env>, history: Option<&Path>) {
+    let mut rl = Editor::<LispHelper>::new();
+    let mut helper = LispHelper::default();
+    helper.refresh(env);
+    rl.set_helper(Some(helper));
+    load_history(&mut rl, history);
+    // Lines of a form that isn't finished yet. They are gathered here rather
+    // than by a `Validator`, which would keep one readline going until the
+    // form is complete: rustyline 9 only draws the prompt on the first line
+    // of an entry, so there would be no ".. " prompt on the others.
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { ">> " } else { ".. " };
+        match rl.readline(&prompt.green()) {
+            Ok(line) if input.is_empty() && line.starts_with(':') => {
+                rl.add_history_entry(line.as_str());
+                if meta_command(&line, env, rl.history()) == Control::Quit {
+                    break
+                }
+                if let Some(helper) = rl.helper_mut() {
+                    helper.refresh(env);
+                }
+            },
+            Ok(line) => {
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+                if lisparse::is_incomplete(&input) {
+                    continue;
+                }
+                rl.add_history_entry(input.as_str());
+                print_value(&Eval(&input, env));
+                input.clear();
+                if let Some(helper) = rl.helper_mut() {
+                    helper.refresh(env);
+                }
+            },
+            Err(ReadlineError::Interrupted) if !input.is_empty() => {
+                // drop the unfinished form, not the session
+                input.clear();
+            },
+            Err(ReadlineError::Interrupted) => {
+                println!("C-c");
+                break
+            },
+            Err(ReadlineError::Eof) => {
+                println!("C-d");
+                break
+            },
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break
+            }
+        }
+    }
+    save_history(&mut rl, history);
+}
+
+/// Prints a result, laid out over lines if it doesn't fit on one.
+fn print_value(value: &LispType) {
+    println!("{}", pretty::pretty(value, pretty::width()));
+}
+
+/// A value the way `:env` and `:doc` show it.
+fn describe(value: &LispType) -> String {
+    match value {
+        LispType::Atom(Atomic::Proc(uf)) => format!("procedure {}", uf.signature()),
+        LispType::Atom(Atomic::Fun(_)) => String::from("builtin"),
+        _ => value.to_string(),
+    }
+}
+
+/// Runs a line starting with `:`.
+pub fn meta_command(line: &str, env: &mut Box<Env>, history: &History) -> Control {
+    let line = line.trim();
+    let (cmd, arg) = match line.split_once(char::is_whitespace) {
+        Some((cmd, arg)) => (cmd, arg.trim()),
+        None => (line, ""),
+    };
+    match cmd {
+        ":help" | ":h" => {
+            for (usage, what) in COMMANDS {
+                println!("{:<12} {}", usage, what);
+            }
+        }
+        ":env" => {
+            for (name, value) in env.globals() {
+                println!("{:<16} {}", name, describe(&value));
+            }
+        }
+        ":doc" => match (lisparse::doc(arg), env.global(arg)) {
+            (Some(doc), _) => println!("{}", doc),
+            (None, Some(value)) => println!("{}: {}", arg, describe(&value)),
+            (None, None) if env.keyword_names().iter().any(|name| &**name == arg) => println!("{}: macro", arg),
+            (None, None) => println!("{}", format!("{} is not defined!", arg).red()),
+        },
+        ":load" => match std::fs::read_to_string(arg) {
+            Ok(src) => match lisparse::load_source(&src, arg, env) {
+                Ok(value) => print_value(&value),
+                Err(err) => println!("{}", backtrace::report(&err).red()),
+            },
+            Err(err) => println!("{}", format!("Can't read {}: {}", arg, err).red()),
+        },
+        ":time" => {
+            let start = Instant::now();
+            let value = Eval(arg, env);
+            let elapsed = start.elapsed();
+            print_value(&value);
+            println!("{}", format!("{:.3?}", elapsed).bright_black());
+        }
+        ":reset" => {
+            let engine = env.engine();
+            **env = lisparse::init_env();
+            env.set_engine(engine);
+            load_init_file(env);
+        }
+        ":history" => {
+            for (i, entry) in history.iter().enumerate() {
+                println!("{:>4}  {}", i + 1, entry);
+            }
+        }
+        ":width" if arg.is_empty() => println!("{}", pretty::width()),
+        ":width" => match arg.parse::<usize>() {
+            Ok(width) if width > 0 => pretty::set_width(width),
+            _ => println!("{}", format!("{} is not a width", arg).red()),
+        },
+        ":quit" | ":q" => return Control::Quit,
+        _ => println!("{}", format!("Unknown command {}, try :help", cmd).red()),
+    }
+    Control::Continue
+}
+
+/// Editor support for the REPL: highlighting, bracket matching and symbol
+/// completion. It only sees a snapshot of the global `Env`, which the REPL
+/// refreshes with `refresh` after every evaluation.
+#[derive(Default)]
+pub struct LispHelper {
+    names: Vec<String>,
+    keywords: HashSet<String>,
+}
+
+impl LispHelper {
+    pub fn refresh(&mut self, env: &Env) {
+        self.keywords = env.keyword_names().iter().map(|name| name.to_string()).collect();
+        let mut names: Vec<String> = env.global_names().iter().map(|name| name.to_string()).collect();
+        names.extend(self.keywords.iter().cloned());
+        names.sort();
+        names.dedup();
+        self.names = names;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Bracket,
+    Quote,
+    Str,
+    Comment,
+    Literal,
+    Keyword,
+    Symbol,
+    Space,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';' | '\'')
+}
+
+/// Splits a line into (start, end, kind) spans the same way the reader
+/// tokenizes it, but keeping everything, whitespace and comments included.
+fn lex(line: &str, keywords: &HashSet<String>) -> Vec<(usize, usize, Kind)> {
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    let n = chars.len();
+    let at = |i: usize| chars.get(i).map(|(_, c)| *c);
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < n {
+        let (start, c) = chars[i];
+        let mut j = i + 1;
+        let kind = match c {
+            '(' | ')' => Kind::Bracket,
+            '\'' => Kind::Quote,
+            '"' => {
+                while let Some(c) = at(j) {
+                    j += if c == '\\' { 2 } else { 1 };
+                    if c == '"' {
+                        break;
+                    }
+                }
+                Kind::Str
+            }
+            ';' => {
+                j = n;
+                Kind::Comment
+            }
+            '#' if at(j) == Some('|') => {
+                let mut depth = 1;
+                j += 1;
+                while depth > 0 && j < n {
+                    match (at(j), at(j + 1)) {
+                        (Some('|'), Some('#')) => { depth -= 1; j += 2; }
+                        (Some('#'), Some('|')) => { depth += 1; j += 2; }
+                        _ => j += 1,
+                    }
+                }
+                Kind::Comment
+            }
+            '#' if at(j) == Some(';') => {
+                j += 1;
+                Kind::Comment
+            }
+            c if c.is_whitespace() => {
+                while at(j).is_some_and(char::is_whitespace) {
+                    j += 1;
+                }
+                Kind::Space
+            }
+            _ => {
+                while at(j).is_some_and(|c| !is_delimiter(c)) {
+                    j += 1;
+                }
+                let end = chars.get(j).map_or(line.len(), |(idx, _)| *idx);
+                let token = &line[start..end];
+                if token == "t" || token == "nil" || parse_number(token).is_some() {
+                    Kind::Literal
+                } else if keywords.contains(token) {
+                    Kind::Keyword
+                } else {
+                    Kind::Symbol
+                }
+            }
+        };
+        let j = j.min(n);
+        let end = chars.get(j).map_or(line.len(), |(idx, _)| *idx);
+        spans.push((start, end, kind));
+        i = j;
+    }
+    spans
+}
+
+/// Byte offsets of the bracket under or just before the cursor and of the
+/// bracket matching it.
+fn matching_bracket(line: &str, spans: &[(usize, usize, Kind)], pos: usize) -> Option<(usize, usize)> {
+    let mut open = Vec::new();
+    let mut pairs = Vec::new();
+    for (start, _, kind) in spans {
+        match (kind, &line[*start..*start+1]) {
+            (Kind::Bracket, "(") => open.push(*start),
+            (Kind::Bracket, _) => pairs.extend(open.pop().map(|o| (o, *start))),
+            _ => {}
+        }
+    }
+    let before = line[..pos].chars().next_back().map(|c| pos - c.len_utf8());
+    [Some(pos), before].into_iter().flatten()
+        .find_map(|at| pairs.iter().find(|(o, c)| *o == at || *c == at).copied())
+}
+
+impl Highlighter for LispHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let spans = lex(line, &self.keywords);
+        let matched = matching_bracket(line, &spans, pos);
+        let mut res = String::with_capacity(line.len() * 2);
+        for (start, end, kind) in spans {
+            let text = &line[start..end];
+            let styled = match kind {
+                Kind::Bracket if matched.is_some_and(|(a, b)| start == a || start == b) => text.bold().reversed(),
+                Kind::Bracket | Kind::Quote => text.cyan(),
+                Kind::Str => text.green(),
+                Kind::Comment => text.bright_black(),
+                Kind::Literal => text.yellow(),
+                Kind::Keyword => text.magenta().bold(),
+                Kind::Symbol | Kind::Space => text.normal(),
+            };
+            res.push_str(&styled.to_string());
+        }
+        Cow::Owned(res)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        // the matching bracket depends on where the cursor is
+        true
+    }
+}
+
+impl Completer for LispHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        if line.starts_with(':') && !line[..pos].contains(char::is_whitespace) {
+            let commands = COMMANDS.iter().map(|(usage, _)| usage.split(' ').next().unwrap());
+            return Ok((0, commands.filter(|cmd| cmd.starts_with(&line[..pos])).map(String::from).collect()));
+        }
+        let start = line[..pos].rfind(is_delimiter).map_or(0, |idx| idx + 1);
+        let prefix = &line[start..pos];
+        let candidates = self.names.iter().filter(|name| name.starts_with(prefix)).cloned().collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for LispHelper {
+    type Hint = String;
+}
+
+impl Validator for LispHelper {}
+
+impl Helper for LispHelper {}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<(&str, Kind)> {
+        let keywords = HashSet::from([String::from("define")]);
+        lex(line, &keywords).into_iter().filter(|(_, _, kind)| *kind != Kind::Space)
+            .map(|(start, end, kind)| (&line[start..end], kind)).collect()
+    }
+
+    #[test]
+    fn test_lex() {
+        assert_eq!(kinds("(define x \"a (b\") ; c"), vec![
+            ("(", Kind::Bracket), ("define", Kind::Keyword), ("x", Kind::Symbol),
+            ("\"a (b\"", Kind::Str), (")", Kind::Bracket), ("; c", Kind::Comment),
+        ]);
+        assert_eq!(kinds("'(1.5 #x1F t) #| (x |# #;y"), vec![
+            ("'", Kind::Quote), ("(", Kind::Bracket), ("1.5", Kind::Literal), ("#x1F", Kind::Literal),
+            ("t", Kind::Literal), (")", Kind::Bracket), ("#| (x |#", Kind::Comment), ("#;", Kind::Comment), ("y", Kind::Symbol),
+        ]);
+        assert_eq!(kinds("\"open \\\""), vec![("\"open \\\"", Kind::Str)]);
+    }
+
+    #[test]
+    fn test_matching_bracket() {
+        let line = "(a (b \")\") c)";
+        let spans = lex(line, &HashSet::new());
+        assert_eq!(matching_bracket(line, &spans, 0), Some((0, 12)));
+        assert_eq!(matching_bracket(line, &spans, 13), Some((0, 12)));
+        assert_eq!(matching_bracket(line, &spans, 3), Some((3, 9)));
+        assert_eq!(matching_bracket(line, &spans, 10), Some((3, 9)));
+        assert_eq!(matching_bracket(line, &spans, 6), None);
+        assert_eq!(matching_bracket("(a", &lex("(a", &HashSet::new()), 0), None);
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut env = rlisp::lisparse::init_env();
+        rlisp::lisparse::eval(&rlisp::lisparse::cvt_to_nested_expression(
+            &rlisp::lisparse::split_cmd_to_vec("(define symbolic 1)"), &mut 0, 0).unwrap(), &mut env).unwrap();
+        let mut helper = LispHelper::default();
+        helper.refresh(&env);
+        let history = rustyline::history::History::new();
+        let ctx = Context::new(&history);
+        let (start, candidates) = helper.complete("(car (sym", 9, &ctx).unwrap();
+        assert_eq!(start, 6);
+        assert_eq!(candidates, vec!["symbol->string", "symbolic"]);
+        assert!(helper.complete("(def", 4, &ctx).unwrap().1.contains(&String::from("define-syntax")));
+        assert_eq!(helper.complete(":he", 3, &ctx).unwrap(), (0, vec![String::from(":help")]));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut env = lisparse::init_env();
+        let mut out = Vec::new();
+        assert!(run_filter("(define sq (lambda (x) (* x x)))\n(sq 3) ; nine\n'(a b)", &mut env, &mut out));
+        assert_eq!(String::from_utf8(out).unwrap(), "9\n(a b)\n");
+        let mut out = Vec::new();
+        assert!(run_filter("(define show display)\n(show \"\")\n(set! show 2)\n'()\n(list 'define 'x)", &mut env, &mut out));
+        assert_eq!(String::from_utf8(out).unwrap(), "()\n(define x)\n");
+        let mut out = Vec::new();
+        assert!(!run_filter("(car undefined)\n(sq 4)", &mut env, &mut out));
+        assert_eq!(String::from_utf8(out).unwrap(), "16\n");
+        assert!(!run_filter("(sq 4", &mut env, &mut Vec::new()));
+    }
+
+    #[test]
+    fn test_expr() {
+        let mut env = lisparse::init_env();
+        let run = |expr: &str, env: &mut Env| {
+            let mut out = Vec::new();
+            run_expr(expr, env, &mut out).map(|_| String::from_utf8(out).unwrap())
+        };
+        assert_eq!(run("(+ 1 2)", &mut env).unwrap(), "3\n");
+        assert_eq!(run("(define x 2) (* x 3)", &mut env).unwrap(), "6\n");
+        assert_eq!(run("(define y 1)", &mut env).unwrap(), "");
+        assert_eq!(run("(display \"\")", &mut env).unwrap(), "");
+        assert_eq!(run("(define p display) (p \"\")", &mut env).unwrap(), "");
+        assert_eq!(run("(begin (define z 1))", &mut env).unwrap(), "");
+        assert_eq!(run("'(display 1)", &mut env).unwrap(), "(display 1)\n");
+        assert!(run("(car 1)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_paths() {
+        fn vars(set: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<OsString> {
+            move |name| set.iter().find(|(var, _)| *var == name).map(|(_, value)| OsString::from(value))
+        }
+        let home = &[("HOME", "/home/u")];
+        assert_eq!(history_path_from(vars(home)), Some(PathBuf::from("/home/u/.local/share/rlisp/history")));
+        assert_eq!(history_path_from(vars(&[("HOME", "/home/u"), ("XDG_DATA_HOME", "/data")])), Some(PathBuf::from("/data/rlisp/history")));
+        assert_eq!(history_path_from(vars(&[("HOME", "/home/u"), ("XDG_DATA_HOME", "data")])), Some(PathBuf::from("/home/u/.local/share/rlisp/history")));
+        assert_eq!(history_path_from(vars(&[("HOME", "/home/u"), ("RLISP_HISTORY", "h.txt")])), Some(PathBuf::from("h.txt")));
+        assert_eq!(history_path_from(vars(&[("HOME", "/home/u"), ("RLISP_HISTORY", "")])), None);
+        assert_eq!(history_path_from(vars(&[])), None);
+        assert_eq!(init_file_from(vars(home)), Some(PathBuf::from("/home/u/.rlisprc")));
+        assert_eq!(init_file_from(vars(&[("HOME", "/home/u"), ("RLISP_INIT", "")])), None);
+    }
+
+    #[test]
+    fn test_meta_commands() {
+        let path = std::env::temp_dir().join(format!("rlisp-load-{}.lisp", std::process::id()));
+        std::fs::write(&path, "(define loaded 1)\n(define sq (lambda (x)\n  (* x x)))\n").unwrap();
+        let mut env = Box::new(lisparse::init_env());
+        let history = History::new();
+        assert_eq!(meta_command(&format!(":load {}", path.display()), &mut env, &history), Control::Continue);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Eval("(sq loaded)", &mut env), LispType::Atom(Atomic::Number(1)));
+        assert_eq!(describe(&env.global("sq").unwrap()), "procedure (sq x)");
+        meta_command(":width 20", &mut env, &history);
+        assert_eq!(pretty::width(), 20);
+        meta_command(":width wide", &mut env, &history);
+        assert_eq!(pretty::width(), 20);
+        meta_command(":reset", &mut env, &history);
+        assert!(env.global("loaded").is_none());
+        assert_eq!(meta_command(":quit", &mut env, &history), Control::Quit);
+    }
+}
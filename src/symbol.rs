@@ -0,0 +1,151 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::rc::Rc;
+
+/// An interned symbol. Equal names intern to the same id, so comparing and
+/// hashing symbols never touches the string. An uninterned symbol shares
+/// the id of its name and has a serial number of its own, so making one
+/// doesn't add to the table.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sym {
+    id: u32,
+    /// 0 for interned symbols
+    serial: u32,
+    /// true for gensyms, which print their serial after the name
+    numbered: bool,
+}
+
+#[derive(Default)]
+struct SymbolTable {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, u32>,
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<SymbolTable> = RefCell::new(SymbolTable::default());
+    static NEXT_SERIAL: Cell<u32> = const { Cell::new(1) };
+}
+
+impl Sym {
+    pub fn intern(name: &str) -> Sym {
+        SYMBOLS.with(|table| {
+            let mut table = table.borrow_mut();
+            if let Some(id) = table.ids.get(name) {
+                return Sym { id: *id, serial: 0, numbered: false };
+            }
+            let id = table.names.len() as u32;
+            let name: Rc<str> = Rc::from(name);
+            table.names.push(name.clone());
+            table.ids.insert(name, id);
+            Sym { id, serial: 0, numbered: false }
+        })
+    }
+
+    /// A fresh symbol printed as `name` that no other symbol, interned or
+    /// not, is equal to.
+    pub fn uninterned(name: &str) -> Sym {
+        Sym::intern(name).fresh()
+    }
+
+    /// A fresh symbol printed like this one.
+    pub fn fresh(self) -> Sym {
+        let serial = NEXT_SERIAL.with(|next| {
+            let serial = next.get();
+            next.set(serial.wrapping_add(1).max(1));
+            serial
+        });
+        Sym { id: self.id, serial, numbered: false }
+    }
+
+    /// A fresh uninterned symbol with a generated name, as returned by `(gensym)`.
+    /// Only the prefix is interned; the serial is printed as the suffix.
+    pub fn gensym(prefix: &str) -> Sym {
+        Sym { numbered: true, ..Sym::intern(prefix).fresh() }
+    }
+
+    pub fn name(self) -> Rc<str> {
+        let name = SYMBOLS.with(|table| table.borrow().names[self.id as usize].clone());
+        if self.numbered {
+            Rc::from(format!("{}{}", name, self.serial))
+        } else {
+            name
+        }
+    }
+}
+
+impl fmt::Display for Sym {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Debug for Sym {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lisparse::*;
+
+    #[test]
+    fn test_interning() {
+        assert_eq!(Sym::intern("foo"), Sym::intern("foo"));
+        assert_ne!(Sym::intern("foo"), Sym::intern("bar"));
+        let g = Sym::uninterned("foo");
+        assert_ne!(g, Sym::intern("foo"));
+        assert_eq!(g.to_string(), "foo");
+        assert_ne!(g, Sym::uninterned("foo"));
+    }
+
+    #[test]
+    fn test_aliases_stay_out_of_the_table() {
+        let mut env = Box::new(init_env());
+        Eval("(define-syntax swap! (syntax-rules () ((_ a b) ((lambda (tmp) (set! a b) (set! b tmp)) a))))", &mut env);
+        Eval("(define x 1)", &mut env);
+        Eval("(define y 2)", &mut env);
+        Eval("(swap! x y)", &mut env);
+        let before = SYMBOLS.with(|table| table.borrow().names.len());
+        for _ in 0..100 {
+            Eval("(swap! x y)", &mut env);
+        }
+        assert_eq!(SYMBOLS.with(|table| table.borrow().names.len()), before);
+        assert_eq!(Eval("(list x y)", &mut env).to_string(), "(2 1)");
+    }
+
+    #[test]
+    fn test_symbol_builtins() {
+        let mut env = Box::new(init_env());
+        assert_eq!(Eval("(symbol->string 'hello)", &mut env), LispType::Atom(Atomic::Str(Rc::from("hello"))));
+        assert_eq!(Eval("(string->symbol \"hello world\")", &mut env), LispType::Atom(Atomic::Symbol(Sym::intern("hello world"))));
+        assert_eq!(Eval("(list \"a \\\"b\\\"\" 'c)", &mut env).to_string(), "(\"a \\\"b\\\"\" c)");
+        Eval("(define g (gensym))", &mut env);
+        assert_ne!(Eval("g", &mut env), Eval("(string->symbol (symbol->string g))", &mut env));
+        assert_ne!(Eval("g", &mut env), Eval("(gensym)", &mut env));
+    }
+
+    #[test]
+    fn test_gensyms_stay_out_of_the_table() {
+        let mut env = Box::new(init_env());
+        Eval("(gensym)", &mut env);
+        Eval("(gensym \"tmp\")", &mut env);
+        let before = SYMBOLS.with(|table| table.borrow().names.len());
+        for _ in 0..100 {
+            Eval("(gensym)", &mut env);
+            Eval("(gensym \"tmp\")", &mut env);
+        }
+        assert_eq!(SYMBOLS.with(|table| table.borrow().names.len()), before);
+        let g = Sym::gensym("tmp");
+        assert!(g.to_string().starts_with("tmp"));
+        assert_ne!(g.to_string(), "tmp");
+        assert_ne!(g, Sym::intern(&g.to_string()));
+    }
+}
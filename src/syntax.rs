@@ -0,0 +1,579 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use crate::backtrace::Span;
+use crate::lisparse::{Atomic, LispType, List};
+use crate::symbol::Sym;
+
+/*
+(define-syntax <keyword> (syntax-rules (<literal>...) (<pattern> <template>)...))
+(define-syntax <keyword> (syntax-rules <ellipsis> (<literal>...) (<pattern> <template>)...))
+ */
+#[derive(Debug)]
+pub struct SyntaxRules {
+    name: Sym,
+    ellipsis: Sym,
+    literals: Vec<Sym>,
+    rules: Vec<(LispType, LispType)>,
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    One(LispType),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<Sym, Binding>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpecialForm {
+    Quote,
+    If,
+    Define,
+    Set,
+    Lambda,
+    DefineSyntax,
+    /// (global sym) refers to the global binding of sym whatever is bound
+    /// locally; the expander writes it for the free identifiers a template
+    /// introduces. It is not a keyword, so user code can't write it.
+    Global,
+}
+
+impl SpecialForm {
+    const ALL: [SpecialForm; 6] = [SpecialForm::Quote, SpecialForm::If, SpecialForm::Define, SpecialForm::Set,
+                                   SpecialForm::Lambda, SpecialForm::DefineSyntax];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpecialForm::Quote => "quote",
+            SpecialForm::If => "if",
+            SpecialForm::Define => "define",
+            SpecialForm::Set => "set!",
+            SpecialForm::Lambda => "lambda",
+            SpecialForm::DefineSyntax => "define-syntax",
+            SpecialForm::Global => "global",
+        }
+    }
+}
+
+/// What a keyword means in the syntactic environment.
+#[derive(Debug, Clone)]
+pub enum Syntax {
+    Special(SpecialForm),
+    Macro(Rc<SyntaxRules>),
+}
+
+/// The syntactic environment, kept apart from the value bindings in `Env`.
+/// Keywords are resolved here before evaluation: the head of every core form
+/// is replaced by `Atomic::Special`, so `eval` never has to look them up and
+/// a variable that happens to be named `if` is just a variable.
+///
+/// It also carries the renaming state needed for hygiene. Every symbol a
+/// template introduces is replaced by a fresh alias; aliases that end up
+/// bound by a `lambda` or `define` keep their unique name, the others are
+/// resolved back to the symbol they were renamed from.
+#[derive(Debug)]
+pub struct SyntaxEnv {
+    keywords: HashMap<Sym, Syntax>,
+    aliases: HashMap<Sym, Sym>,
+    bound_aliases: HashSet<Sym>,
+}
+
+fn symbol_of(expr: &LispType) -> Option<Sym> {
+    match expr {
+        LispType::Atom(Atomic::Symbol(s)) => Some(*s),
+        _ => None,
+    }
+}
+
+impl SyntaxRules {
+    fn parse(name: Sym, spec: &LispType) -> Result<SyntaxRules, String> {
+        let usage = "Usage: (syntax-rules (literal...) (pattern template)...)";
+        let list = match spec {
+            LispType::List(list) if list.first().and_then(symbol_of) == Some(Sym::intern("syntax-rules")) => list,
+            _ => return Err(format!("Syntax Error: {}", usage)),
+        };
+        let (ellipsis, rest) = match list.get(1) {
+            Some(LispType::Atom(Atomic::Symbol(s))) => (*s, &list[2..]),
+            _ => (Sym::intern("..."), &list[1..]),
+        };
+        let literals = match rest.first() {
+            Some(LispType::List(lits)) => {
+                let mut literals = Vec::new();
+                for lit in lits {
+                    match symbol_of(lit) {
+                        Some(s) => literals.push(s),
+                        None => return Err(format!("Syntax Error: literal {} is not a symbol", lit)),
+                    }
+                }
+                literals
+            }
+            Some(LispType::Atom(Atomic::nil)) => Vec::new(),
+            _ => return Err(format!("Syntax Error: {}", usage)),
+        };
+        let mut rules = Vec::new();
+        for rule in &rest[1..] {
+            match rule {
+                LispType::List(pair) if pair.len() == 2 => {
+                    if !matches!(pair[0], LispType::List(_)) {
+                        return Err(format!("Syntax Error: pattern {} should be a list", pair[0]));
+                    }
+                    rules.push((pair[0].clone(), pair[1].clone()));
+                }
+                _ => return Err(format!("Syntax Error: {}", usage)),
+            }
+        }
+        Ok(SyntaxRules { name, ellipsis, literals, rules })
+    }
+
+    fn is_ellipsis(&self, expr: &LispType) -> bool {
+        symbol_of(expr) == Some(self.ellipsis)
+    }
+
+    fn pattern_vars(&self, pat: &LispType, vars: &mut Vec<Sym>) {
+        match pat {
+            LispType::Atom(Atomic::Symbol(s)) if *s != Sym::intern("_") && *s != self.ellipsis && !self.literals.contains(s) => {
+                vars.push(*s);
+            }
+            LispType::List(list) => {
+                for p in list {
+                    self.pattern_vars(p, vars);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn match_pattern(&self, pat: &LispType, form: &LispType, syn: &SyntaxEnv, binds: &mut Bindings) -> bool {
+        match pat {
+            LispType::Atom(Atomic::Symbol(s)) => {
+                if *s == Sym::intern("_") {
+                    true
+                } else if self.literals.contains(s) {
+                    symbol_of(form).map(|f| syn.resolve(f)) == Some(*s)
+                } else {
+                    binds.insert(*s, Binding::One(form.clone()));
+                    true
+                }
+            }
+            LispType::List(pats) => {
+                let forms = match form {
+                    LispType::List(forms) => &forms[..],
+                    LispType::Atom(Atomic::nil) => &[],
+                    _ => return false,
+                };
+                match pats.iter().position(|p| self.is_ellipsis(p)) {
+                    Some(k) if k > 0 => {
+                        let after = pats.len() - k - 1;
+                        if forms.len() < k - 1 + after {
+                            return false;
+                        }
+                        let tail = forms.len() - after;
+                        for i in 0..k - 1 {
+                            if !self.match_pattern(&pats[i], &forms[i], syn, binds) {
+                                return false;
+                            }
+                        }
+                        for i in 0..after {
+                            if !self.match_pattern(&pats[k + 1 + i], &forms[tail + i], syn, binds) {
+                                return false;
+                            }
+                        }
+                        let mut vars = Vec::new();
+                        self.pattern_vars(&pats[k - 1], &mut vars);
+                        let mut seqs: Vec<Vec<Binding>> = vec![Vec::new(); vars.len()];
+                        for f in &forms[k - 1..tail] {
+                            let mut sub = Bindings::new();
+                            if !self.match_pattern(&pats[k - 1], f, syn, &mut sub) {
+                                return false;
+                            }
+                            for (j, var) in vars.iter().enumerate() {
+                                seqs[j].push(sub.remove(var).unwrap());
+                            }
+                        }
+                        for (var, seq) in vars.into_iter().zip(seqs) {
+                            binds.insert(var, Binding::Many(seq));
+                        }
+                        true
+                    }
+                    _ => {
+                        pats.len() == forms.len()
+                            && pats.iter().zip(forms).all(|(p, f)| self.match_pattern(p, f, syn, binds))
+                    }
+                }
+            }
+            _ => pat == form,
+        }
+    }
+
+    fn instantiate(&self, tmpl: &LispType, binds: &Bindings, renames: &mut HashMap<Sym, Sym>,
+                   syn: &mut SyntaxEnv, escaped: bool) -> Result<LispType, String> {
+        match tmpl {
+            LispType::Atom(Atomic::Symbol(s)) => match binds.get(s) {
+                Some(Binding::One(form)) => Ok(form.clone()),
+                Some(Binding::Many(_)) => Err(format!("Syntax Error: pattern variable {} used without ellipsis in {}", s, self.name)),
+                None => {
+                    let alias = *renames.entry(*s).or_insert_with(|| syn.fresh_alias(*s));
+                    Ok(LispType::Atom(Atomic::Symbol(alias)))
+                }
+            },
+            LispType::List(list) => {
+                if !escaped && list.len() == 2 && self.is_ellipsis(&list[0]) {
+                    return self.instantiate(&list[1], binds, renames, syn, true);
+                }
+                let mut res = Vec::new();
+                let mut i = 0;
+                while i < list.len() {
+                    let mut depth = 0;
+                    while !escaped && i + depth + 1 < list.len() && self.is_ellipsis(&list[i + depth + 1]) {
+                        depth += 1;
+                    }
+                    if depth == 0 {
+                        res.push(self.instantiate(&list[i], binds, renames, syn, escaped)?);
+                    } else {
+                        res.extend(self.instantiate_ellipsis(&list[i], binds, renames, syn, depth)?);
+                    }
+                    i += depth + 1;
+                }
+                Ok(LispType::List(res.into()))
+            }
+            _ => Ok(tmpl.clone()),
+        }
+    }
+
+    fn instantiate_ellipsis(&self, tmpl: &LispType, binds: &Bindings, renames: &mut HashMap<Sym, Sym>,
+                            syn: &mut SyntaxEnv, depth: usize) -> Result<Vec<LispType>, String> {
+        if depth == 0 {
+            return Ok(vec![self.instantiate(tmpl, binds, renames, syn, false)?]);
+        }
+        let mut vars = Vec::new();
+        self.pattern_vars(tmpl, &mut vars);
+        vars.retain(|v| matches!(binds.get(v), Some(Binding::Many(_))));
+        if vars.is_empty() {
+            return Err(format!("Syntax Error: no pattern variable before ellipsis in {}", self.name));
+        }
+        let len = match &binds[&vars[0]] { Binding::Many(seq) => seq.len(), _ => 0 };
+        let mut res = Vec::new();
+        for i in 0..len {
+            let mut sub = binds.clone();
+            for var in &vars {
+                if let Binding::Many(seq) = &binds[var] {
+                    if seq.len() != len {
+                        return Err(format!("Syntax Error: incompatible ellipsis match counts in {}", self.name));
+                    }
+                    sub.insert(*var, seq[i].clone());
+                }
+            }
+            res.extend(self.instantiate_ellipsis(tmpl, &sub, renames, syn, depth - 1)?);
+        }
+        Ok(res)
+    }
+
+    fn apply(&self, form: &LispType, syn: &mut SyntaxEnv) -> Result<LispType, String> {
+        let args = match form {
+            LispType::List(list) => LispType::List(list.tail()),
+            _ => return Err(format!("Syntax Error: bad use of {}", self.name)),
+        };
+        for (pat, tmpl) in &self.rules {
+            let pats = match pat {
+                LispType::List(pats) if !pats.is_empty() => LispType::List(pats.tail()),
+                _ => LispType::List(Vec::new().into()),
+            };
+            let mut binds = Bindings::new();
+            if self.match_pattern(&pats, &args, syn, &mut binds) {
+                let expanded = self.instantiate(tmpl, &binds, &mut HashMap::new(), syn, false)?;
+                return Ok(locate(expanded, &list_span(form)));
+            }
+        }
+        Err(format!("Syntax Error: no syntax rule of {} matches {}", self.name, form))
+    }
+}
+
+fn list_span(form: &LispType) -> Option<Rc<Span>> {
+    match form {
+        LispType::List(list) => list.span(),
+        _ => None,
+    }
+}
+
+/// Gives the lists a template made the span of the macro use, so that the
+/// calls in them are reported there. Lists from the use keep their own.
+fn locate(expr: LispType, span: &Option<Rc<Span>>) -> LispType {
+    match expr {
+        LispType::List(list) if list.span().is_none() => {
+            let items: List = list.iter().map(|e| locate(e.clone(), span)).collect();
+            LispType::List(items.with_span(span.clone()))
+        }
+        _ => expr,
+    }
+}
+
+impl Default for SyntaxEnv {
+    fn default() -> Self {
+        let keywords = SpecialForm::ALL.iter()
+            .map(|form| (Sym::intern(form.name()), Syntax::Special(*form)))
+            .collect();
+        SyntaxEnv { keywords, aliases: HashMap::new(), bound_aliases: HashSet::new() }
+    }
+}
+
+impl SyntaxEnv {
+    /// Every keyword currently defined, special forms and macros alike.
+    pub fn keywords(&self) -> impl Iterator<Item = Sym> + '_ {
+        self.keywords.keys().copied()
+    }
+
+    /// Aliases are uninterned, so they can't collide with anything the user
+    /// writes, but they still print like the symbol they stand for.
+    fn fresh_alias(&mut self, name: Sym) -> Sym {
+        let alias = name.fresh();
+        self.aliases.insert(alias, name);
+        alias
+    }
+
+    /// Follows the alias chain of a symbol back to the name written by the user.
+    fn resolve(&self, sym: Sym) -> Sym {
+        let mut s = sym;
+        while let Some(orig) = self.aliases.get(&s) {
+            s = *orig;
+        }
+        s
+    }
+
+    fn is_bound(&self, sym: Sym, scope: &[Sym]) -> bool {
+        scope.contains(&sym) || self.bound_aliases.contains(&sym)
+    }
+
+    /// Resolves a symbol in reference position: aliases that nothing bound refer
+    /// to the binding visible where the macro was defined, i.e. the global one.
+    fn reference(&self, sym: Sym, scope: &[Sym]) -> Sym {
+        if self.aliases.contains_key(&sym) && !self.is_bound(sym, scope) {
+            self.resolve(sym)
+        } else {
+            sym
+        }
+    }
+
+    /// `written` as a variable, once `reference` made it `sym`: a free alias
+    /// becomes (global sym), so that a local of the same name at the use
+    /// site can't capture it.
+    fn variable(written: Sym, sym: Sym) -> LispType {
+        if written == sym {
+            LispType::Atom(Atomic::Symbol(sym))
+        } else {
+            LispType::List(vec![LispType::Atom(Atomic::Special(SpecialForm::Global)), LispType::Atom(Atomic::Symbol(sym))].into())
+        }
+    }
+
+    fn strip_aliases(&self, datum: &LispType) -> LispType {
+        match datum {
+            LispType::Atom(Atomic::Symbol(s)) => LispType::Atom(Atomic::Symbol(self.resolve(*s))),
+            LispType::List(list) => LispType::List(list.iter().map(|e| self.strip_aliases(e)).collect()),
+            _ => datum.clone(),
+        }
+    }
+
+    pub fn expand(&mut self, expr: &LispType) -> Result<LispType, String> {
+        self.expand_expr(expr, &mut Vec::new(), false)
+    }
+
+    /// Expands `expr` with the variables of `scope` bound around it;
+    /// `in_lambda` tells a form in a lambda without parameters from a
+    /// top-level one.
+    fn expand_expr(&mut self, expr: &LispType, scope: &mut Vec<Sym>, in_lambda: bool) -> Result<LispType, String> {
+        let list = match expr {
+            LispType::Atom(Atomic::Symbol(s)) => {
+                let sym = self.reference(*s, scope);
+                if !self.is_bound(sym, scope) && self.keywords.contains_key(&sym) {
+                    return Err(format!("Syntax Error: keyword {} can't be used as an expression", sym));
+                }
+                return Ok(SyntaxEnv::variable(*s, sym));
+            }
+            LispType::List(list) if !list.is_empty() => list,
+            _ => return Ok(expr.clone()),
+        };
+        let keyword = match list.first().and_then(symbol_of) {
+            Some(head) if !scope.contains(&head) => self.reference(head, scope),
+            _ => return self.expand_list(list, scope, in_lambda),
+        };
+        let located = |res: Vec<LispType>| LispType::List(List::from(res).with_span(list.span()));
+        let form = match self.keywords.get(&keyword).cloned() {
+            Some(Syntax::Macro(rules)) => {
+                let expanded = rules.apply(expr, self)?;
+                return self.expand_expr(&expanded, scope, in_lambda);
+            }
+            Some(Syntax::Special(form)) => form,
+            None => return self.expand_list(list, scope, in_lambda),
+        };
+        let head = LispType::Atom(Atomic::Special(form));
+        match form {
+            SpecialForm::Quote => {
+                let mut res = vec![head];
+                res.extend(list[1..].iter().map(|datum| self.strip_aliases(datum)));
+                Ok(LispType::List(res.into()))
+            }
+            SpecialForm::DefineSyntax => {
+                match (list.get(1).and_then(symbol_of), list.get(2)) {
+                    (Some(name), Some(spec)) => {
+                        let name = self.resolve(name);
+                        let rules = SyntaxRules::parse(name, spec)?;
+                        self.keywords.insert(name, Syntax::Macro(Rc::new(rules)));
+                        Ok(LispType::Atom(Atomic::nil))
+                    }
+                    _ => Err(String::from("Syntax Error: Usage: (define-syntax keyword (syntax-rules ...))")),
+                }
+            }
+            SpecialForm::Lambda => {
+                let saved = scope.len();
+                if let Some(LispType::List(params)) = list.get(1) {
+                    scope.extend(params.iter().filter_map(symbol_of));
+                }
+                let mut res = vec![head];
+                if let Some(params) = list.get(1) {
+                    res.push(params.clone());
+                }
+                for body in list.iter().skip(2) {
+                    res.push(self.expand_expr(body, scope, true)?);
+                }
+                scope.truncate(saved);
+                Ok(located(res))
+            }
+            SpecialForm::Define | SpecialForm::Set => {
+                let name = match list.get(1) {
+                    Some(LispType::Atom(Atomic::Symbol(name))) => *name,
+                    _ => return Err(format!("Syntax Error: Usage: ({} symbol expr)", form.name())),
+                };
+                let name = if form == SpecialForm::Set {
+                    return self.expand_set(name, list, scope, in_lambda);
+                } else if !in_lambda {
+                    // a global definition shadows any keyword of the same name
+                    if self.aliases.contains_key(&name) {
+                        self.bound_aliases.insert(name);
+                    }
+                    self.keywords.remove(&name);
+                    name
+                } else {
+                    scope.push(name);
+                    name
+                };
+                let mut res = vec![head, LispType::Atom(Atomic::Symbol(name))];
+                for value in list.iter().skip(2) {
+                    res.push(self.expand_expr(value, scope, in_lambda)?);
+                }
+                Ok(located(res))
+            }
+            SpecialForm::If => {
+                let mut res = vec![head];
+                for e in &list[1..] {
+                    res.push(self.expand_expr(e, scope, in_lambda)?);
+                }
+                Ok(located(res))
+            }
+            SpecialForm::Global => unreachable!("global is not a keyword"),
+        }
+    }
+
+    fn expand_set(&mut self, name: Sym, list: &List, scope: &mut Vec<Sym>, in_lambda: bool) -> Result<LispType, String> {
+        let mut res = vec![LispType::Atom(Atomic::Special(SpecialForm::Set)), SyntaxEnv::variable(name, self.reference(name, scope))];
+        for value in list.iter().skip(2) {
+            res.push(self.expand_expr(value, scope, in_lambda)?);
+        }
+        Ok(LispType::List(List::from(res).with_span(list.span())))
+    }
+
+    fn expand_list(&mut self, list: &List, scope: &mut Vec<Sym>, in_lambda: bool) -> Result<LispType, String> {
+        let mut res = Vec::new();
+        for e in list {
+            res.push(self.expand_expr(e, scope, in_lambda)?);
+        }
+        Ok(LispType::List(List::from(res).with_span(list.span())))
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use crate::lisparse::*;
+
+    #[test]
+    fn test_simple_macro() {
+        let mut env = Box::new(init_env());
+        Eval("(define-syntax unless (syntax-rules () ((_ c e1 e2) (if c e2 e1))))", &mut env);
+        assert_eq!(Eval("(unless (> 1 2) 10 20)", &mut env), LispType::Atom(Atomic::Number(10)));
+        assert_eq!(Eval("(unless (< 1 2) 10 20)", &mut env), LispType::Atom(Atomic::Number(20)));
+    }
+
+    #[test]
+    fn test_ellipsis() {
+        let mut env = Box::new(init_env());
+        Eval("(define-syntax my-let (syntax-rules () ((_ ((n v) ...) body) ((lambda (n ...) body) v ...))))", &mut env);
+        assert_eq!(Eval("(my-let ((x 1) (y 2) (z 3)) (+ x y z))", &mut env), LispType::Atom(Atomic::Number(6)));
+        Eval("(define-syntax rev-args (syntax-rules () ((_ f a ... b) (f b a ...))))", &mut env);
+        assert_eq!(format!("{}", Eval("(rev-args list 1 2 3)", &mut env)), "(3 1 2)");
+        Eval("(define-syntax flat (syntax-rules () ((_ (a ...) ...) (list a ... ...))))", &mut env);
+        assert_eq!(format!("{}", Eval("(flat (1 2) (3) (4 5))", &mut env)), "(1 2 3 4 5)");
+    }
+
+    #[test]
+    fn test_recursive_macro_and_literals() {
+        let mut env = Box::new(init_env());
+        Eval("(define-syntax my-cond (syntax-rules (else) ((_ (else e)) e) ((_ (c e) rest ...) (if c e (my-cond rest ...)))))", &mut env);
+        assert_eq!(Eval("(my-cond ((> 1 2) 1) ((> 1 3) 2) (else 3))", &mut env), LispType::Atom(Atomic::Number(3)));
+        assert_eq!(Eval("(my-cond ((> 1 2) 1) ((< 1 3) 2) (else 3))", &mut env), LispType::Atom(Atomic::Number(2)));
+    }
+
+    #[test]
+    fn test_hygiene() {
+        let mut env = Box::new(init_env());
+        Eval("(define-syntax my-or (syntax-rules () ((_ a b) ((lambda (tmp) (if tmp tmp b)) a))))", &mut env);
+        Eval("(define tmp 5)", &mut env);
+        assert_eq!(Eval("(my-or nil tmp)", &mut env), LispType::Atom(Atomic::Number(5)));
+        assert_eq!(Eval("(my-or t tmp)", &mut env), LispType::Atom(Atomic::t));
+        assert_eq!(format!("{}", Eval("(my-or nil '(tmp))", &mut env)), "(tmp)");
+    }
+
+    #[test]
+    fn test_free_identifiers_refer_to_globals() {
+        let mut env = Box::new(init_env());
+        Eval("(define-syntax my-first (syntax-rules () ((_ x) (car x))))", &mut env);
+        assert_eq!(Eval("((lambda (car) (my-first '(1 2))) 5)", &mut env), LispType::Atom(Atomic::Number(1)));
+        Eval("(define count 0)", &mut env);
+        Eval("(define-syntax bump! (syntax-rules () ((_) (set! count (+ count 1)))))", &mut env);
+        assert_eq!(Eval("((lambda (count) (bump!) count) 10)", &mut env), LispType::Atom(Atomic::Number(10)));
+        assert_eq!(Eval("count", &mut env), LispType::Atom(Atomic::Number(1)));
+        assert_eq!(Eval("((lambda (%guard) (guard (e (t 'caught)) (raise 'x))) 5)", &mut env).to_string(), "caught");
+        // global is what the expander writes, not a keyword
+        assert_eq!(Eval("((lambda (global) global) 3)", &mut env), LispType::Atom(Atomic::Number(3)));
+    }
+
+    #[test]
+    fn test_special_forms_are_syntactic() {
+        let mut env = Box::new(init_env());
+        Eval("(define x 'if)", &mut env);
+        assert_eq!(Eval("(x t 1 2)", &mut env), LispType::Atom(Atomic::nil));
+        assert_eq!(Eval("((lambda (if) (+ if 1)) 41)", &mut env), LispType::Atom(Atomic::Number(42)));
+        Eval("(define-syntax my-if (syntax-rules () ((_ c a b) (if c a b))))", &mut env);
+        assert_eq!(Eval("((lambda (if) (my-if t if 0)) 7)", &mut env), LispType::Atom(Atomic::Number(7)));
+    }
+
+    #[test]
+    fn test_define_in_lambda_without_params() {
+        let mut env = Box::new(init_env());
+        assert_eq!(Eval("((lambda () (define if 5) if))", &mut env), LispType::Atom(Atomic::Number(5)));
+        assert_eq!(Eval("(if t 1 2)", &mut env), LispType::Atom(Atomic::Number(1)));
+        Eval("(define-syntax twice (syntax-rules () ((_ e) (begin e e))))", &mut env);
+        assert_eq!(Eval("((lambda () (define twice 3) twice))", &mut env), LispType::Atom(Atomic::Number(3)));
+        assert!(eval_source("twice", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_bad_specs() {
+        let mut env = Box::new(init_env());
+        for src in ["(define-syntax foo ())", "(define-syntax foo nil)", "(define-syntax foo (() ()))"] {
+            let err = eval_source(src, &mut env).unwrap_err();
+            assert!(err.to_string().starts_with("Syntax Error: Usage: (syntax-rules"), "{}: {}", src, err);
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::rc::Rc;
+use crate::backtrace::{Call, Callee, Span};
+use crate::compile::{Expr, Lambda};
+use crate::continuation::{self, Continuation};
+use crate::lisparse::{call_at, error, Atomic, Env, Frame, LispError, LispResult, LispType, Proc};
+use crate::port::current_output;
+use crate::symbol::Sym;
+
+/// Instructions of the stack machine. Operands index into the tables of
+/// the `Chunk` they belong to, or address a local slot like `Expr::Local`.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Const(usize),
+    LocalRef(usize, usize),
+    LocalSet(usize, usize),
+    LocalDef(usize),
+    GlobalRef(usize),
+    GlobalSet(usize),
+    GlobalDef(usize),
+    /// jumps when the value on top of the stack is anything but `t`
+    JumpIfFalse(usize),
+    Jump(usize),
+    Closure(usize),
+    Call(usize),
+    TailCall(usize),
+    Return,
+    Pop,
+}
+
+/// Bytecode of a procedure body or of a top-level form.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    name: String,
+    code: Vec<Op>,
+    consts: Vec<LispType>,
+    names: Vec<Sym>,
+    lambdas: Vec<Rc<Lambda>>,
+    /// where the calls are written, by the index of their op
+    spans: Vec<Option<Rc<Span>>>,
+}
+
+impl Chunk {
+    fn emit(&mut self, op: Op) -> usize {
+        self.emit_at(op, None)
+    }
+
+    fn emit_at(&mut self, op: Op, span: Option<Rc<Span>>) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match &mut self.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn name_index(&mut self, name: Sym) -> usize {
+        match self.names.iter().position(|n| *n == name) {
+            Some(idx) => idx,
+            None => {
+                self.names.push(name);
+                self.names.len() - 1
+            }
+        }
+    }
+
+    fn const_index(&mut self, value: &LispType) -> usize {
+        self.consts.push(value.clone());
+        self.consts.len() - 1
+    }
+
+    fn compile(&mut self, expr: &Expr, tail: bool) {
+        match expr {
+            Expr::Const(value) => {
+                let k = self.const_index(value);
+                self.emit(Op::Const(k));
+            }
+            Expr::Local(depth, idx) => { self.emit(Op::LocalRef(*depth, *idx)); }
+            Expr::Global(sym) => {
+                let k = self.name_index(*sym);
+                self.emit(Op::GlobalRef(k));
+            }
+            Expr::SetLocal(depth, idx, value) => {
+                self.compile(value, false);
+                self.emit(Op::LocalSet(*depth, *idx));
+            }
+            Expr::DefineLocal(idx, value) => {
+                self.compile(value, false);
+                self.emit(Op::LocalDef(*idx));
+            }
+            Expr::SetGlobal(sym, value) => {
+                self.compile(value, false);
+                let k = self.name_index(*sym);
+                self.emit(Op::GlobalSet(k));
+            }
+            Expr::DefineGlobal(sym, value) => {
+                self.compile(value, false);
+                let k = self.name_index(*sym);
+                self.emit(Op::GlobalDef(k));
+            }
+            Expr::If(test, conseq, alt) => {
+                self.compile(test, false);
+                let to_alt = self.emit(Op::JumpIfFalse(0));
+                self.compile(conseq, tail);
+                if tail {
+                    // both branches end with a return of their own
+                    self.patch(to_alt);
+                    self.compile(alt, true);
+                } else {
+                    let to_end = self.emit(Op::Jump(0));
+                    self.patch(to_alt);
+                    self.compile(alt, false);
+                    self.patch(to_end);
+                }
+                return;
+            }
+            Expr::Lambda(lambda) => {
+                self.lambdas.push(lambda.clone());
+                self.emit(Op::Closure(self.lambdas.len() - 1));
+            }
+            Expr::Call(f, args, at) => {
+                self.compile(f, false);
+                for arg in args {
+                    self.compile(arg, false);
+                }
+                if tail {
+                    self.emit_at(Op::TailCall(args.len()), at.clone());
+                    return;
+                }
+                self.emit_at(Op::Call(args.len()), at.clone());
+            }
+        }
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn compile_body(name: &str, body: &[Expr]) -> Chunk {
+        let mut chunk = Chunk { name: name.to_string(), ..Chunk::default() };
+        let (last, init) = body.split_last().expect("lambda bodies are never empty");
+        for expr in init {
+            chunk.compile(expr, false);
+            chunk.emit(Op::Pop);
+        }
+        chunk.compile(last, true);
+        chunk
+    }
+}
+
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.name)?;
+        for (ip, op) in self.code.iter().enumerate() {
+            let comment = match op {
+                Op::Const(k) => format!("; {}", self.consts[*k]),
+                Op::GlobalRef(k) | Op::GlobalSet(k) | Op::GlobalDef(k) => format!("; {}", self.names[*k]),
+                Op::Closure(k) => format!("; {}", lambda_name(&self.lambdas[*k])),
+                _ => String::new(),
+            };
+            writeln!(f, "{:>5}  {:<20}{}", ip, format!("{:?}", op), comment)?;
+        }
+        for lambda in &self.lambdas {
+            write!(f, "{}", bytecode(lambda))?;
+        }
+        Ok(())
+    }
+}
+
+fn lambda_name(lambda: &Lambda) -> String {
+    match lambda.name {
+        Some(name) => format!("#<procedure {}>", name),
+        None => String::from("#<procedure lambda>"),
+    }
+}
+
+/// The body of a lambda compiled to bytecode; done once, on first use.
+fn bytecode(lambda: &Lambda) -> &Rc<Chunk> {
+    lambda.bytecode.get_or_init(|| Rc::new(Chunk::compile_body(&lambda_name(lambda), &lambda.body)))
+}
+
+#[derive(Clone)]
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    frame: Option<Rc<Frame>>,
+    /// how many calls of the activation are on the backtrace below this one's
+    depth: usize,
+}
+
+/// What a continuation captured in the VM goes back to: the frames of the
+/// run it was captured in, the values they had pushed and their calls on
+/// the backtrace, as they were then.
+#[derive(Clone)]
+pub struct Snapshot {
+    stack: Vec<LispType>,
+    frames: Vec<CallFrame>,
+    calls: Vec<Call>,
+}
+
+impl Snapshot {
+    /// The frames it keeps alive, for the collector.
+    pub(crate) fn frames(&self) -> impl Iterator<Item = &Rc<Frame>> {
+        self.frames.iter().filter_map(|cf| cf.frame.as_ref())
+    }
+    pub(crate) fn values(&self) -> &[LispType] {
+        &self.stack
+    }
+}
+
+/// Calls a compiled procedure with already evaluated arguments. The call
+/// is the last one on the backtrace, and its tail calls take its place.
+pub fn call(uf: &Rc<Proc>, args: Vec<LispType>, env: &mut Env) -> LispResult {
+    let base = env.calls.depth().saturating_sub(1);
+    execute(bytecode(&uf.lambda).clone(), Some(uf.bind(args)?), base, env)
+}
+
+/// Compiles a top-level form to bytecode and runs it on the VM.
+pub fn run(expr: &Expr, env: &mut Env) -> LispResult {
+    let chunk = Rc::new(Chunk::compile_body("top-level", std::slice::from_ref(expr)));
+    let base = env.calls.depth();
+    execute(chunk, None, base, env)
+}
+
+fn execute(chunk: Rc<Chunk>, frame: Option<Rc<Frame>>, base: usize, env: &mut Env) -> LispResult {
+    run_activation(continuation::new_activation(), base, Vec::new(), vec![CallFrame { chunk, ip: 0, frame, depth: 0 }], env)
+}
+
+/// Runs what `snapshot` captured again, as activation `id`, with `value`
+/// returned by the call/cc.
+pub(crate) fn resume(id: u64, snapshot: &Snapshot, value: LispType, env: &mut Env) -> LispResult {
+    if snapshot.frames.is_empty() {
+        return Ok(value);
+    }
+    let mut stack = snapshot.stack.clone();
+    stack.push(value);
+    let base = env.calls.depth();
+    env.calls.restore(base, &snapshot.calls);
+    run_activation(id, base, stack, snapshot.frames.clone(), env)
+}
+
+/// Runs `frames`, whose calls are on the backtrace from `base` on, until
+/// the first of them returns. A continuation of this activation called from
+/// in there unwinds to here and replaces the frames and the stack with the
+/// ones it captured.
+fn run_activation(id: u64, base: usize, mut stack: Vec<LispType>, mut frames: Vec<CallFrame>, env: &mut Env) -> LispResult {
+    continuation::running(id, || loop {
+        match interpret(id, base, &mut stack, &mut frames, env) {
+            Err(LispError::Throw(k, value)) if k.activation == id => {
+                let snapshot = k.resume.as_ref().expect("continuations of the VM have a snapshot");
+                stack = snapshot.stack.clone();
+                frames = snapshot.frames.clone();
+                env.calls.restore(base, &snapshot.calls);
+                if frames.is_empty() {
+                    return Ok(value);
+                }
+                stack.push(value);
+            }
+            res => return res,
+        }
+    })
+}
+
+fn interpret(id: u64, base: usize, stack: &mut Vec<LispType>, frames: &mut Vec<CallFrame>, env: &mut Env) -> LispResult {
+    loop {
+        let cf = frames.last_mut().unwrap();
+        let op = cf.chunk.code[cf.ip];
+        cf.ip += 1;
+        match op {
+            Op::Const(k) => stack.push(cf.chunk.consts[k].clone()),
+            Op::LocalRef(depth, idx) => {
+                let frame = Frame::ancestor(cf.frame.as_ref().unwrap(), depth);
+                let value = frame.slots.borrow()[idx].clone();
+                stack.push(value);
+            }
+            Op::LocalSet(depth, idx) => {
+                let value = stack.pop().unwrap();
+                Frame::ancestor(cf.frame.as_ref().unwrap(), depth).slots.borrow_mut()[idx] = value;
+                stack.push(LispType::Atom(Atomic::nil));
+            }
+            Op::LocalDef(idx) => {
+                let value = stack.pop().unwrap();
+                cf.frame.as_ref().unwrap().slots.borrow_mut()[idx] = value;
+                stack.push(LispType::Atom(Atomic::nil));
+            }
+            Op::GlobalRef(k) => stack.push(env.lookup_symbol(cf.chunk.names[k])?),
+            Op::GlobalSet(k) => {
+                let value = stack.pop().unwrap();
+                env.set_symbol(cf.chunk.names[k], &value)?;
+                stack.push(LispType::Atom(Atomic::nil));
+            }
+            Op::GlobalDef(k) => {
+                let value = stack.pop().unwrap();
+                env.add_symbol(cf.chunk.names[k], &value)?;
+                stack.push(LispType::Atom(Atomic::nil));
+            }
+            Op::JumpIfFalse(to) => {
+                if !matches!(stack.pop().unwrap(), LispType::Atom(Atomic::t)) {
+                    cf.ip = to;
+                }
+            }
+            Op::Jump(to) => cf.ip = to,
+            Op::Closure(k) => {
+                let proc = Proc::new(cf.chunk.lambdas[k].clone(), cf.frame.clone());
+                stack.push(LispType::Atom(Atomic::Proc(proc)));
+            }
+            Op::Call(argc) | Op::TailCall(argc) => {
+                let args = stack.split_off(stack.len() - argc);
+                let f = stack.pop().unwrap();
+                let tail = matches!(op, Op::TailCall(_));
+                let at = cf.chunk.spans[cf.ip - 1].clone();
+                // a tail call takes the place of the caller, on the backtrace too
+                let depth = if tail { cf.depth } else { env.calls.depth() - base };
+                let (f, args) = match f {
+                    // called directly, call/cc captures the frames so that its continuation can be resumed
+                    LispType::Atom(Atomic::Fun(builtin)) if continuation::is_call_cc(&builtin) && args.len() == 1 => {
+                        let mut saved = frames.clone();
+                        if tail {
+                            saved.pop();
+                        }
+                        let calls = env.calls.from(base)[..depth].to_vec();
+                        let k = Continuation { activation: id, resume: Some(Snapshot { stack: stack.clone(), frames: saved, calls }) };
+                        (args[0].clone(), vec![continuation::value(k)])
+                    }
+                    f => (f, args),
+                };
+                match f {
+                    LispType::Atom(Atomic::Proc(uf)) => {
+                        env.calls.leave(base + depth);
+                        env.calls.enter(Callee::proc(&uf), at);
+                        let callee = CallFrame { chunk: bytecode(&uf.lambda).clone(), ip: 0, frame: Some(uf.bind(args)?), depth };
+                        if tail {
+                            *frames.last_mut().unwrap() = callee;
+                        } else {
+                            frames.push(callee);
+                        }
+                    }
+                    _ => {
+                        let value = call_at(f, args, at, env)?;
+                        if tail {
+                            env.calls.leave(base + depth);
+                            frames.pop();
+                            if frames.is_empty() {
+                                return Ok(value);
+                            }
+                        }
+                        stack.push(value);
+                    }
+                }
+            }
+            Op::Return => {
+                let value = stack.pop().unwrap();
+                env.calls.leave(base + cf.depth);
+                frames.pop();
+                if frames.is_empty() {
+                    return Ok(value);
+                }
+                stack.push(value);
+            }
+            Op::Pop => { stack.pop(); }
+        }
+    }
+}
+
+/// `(disassemble proc)` writes the bytecode of a procedure to the current
+/// output port.
+pub fn disassemble(args: Vec<LispType>, _env: &mut Env) -> LispResult {
+    match args.as_slice() {
+        [LispType::Atom(Atomic::Proc(uf))] => {
+            current_output().write_str(&bytecode(&uf.lambda).to_string())?;
+            Ok(LispType::Atom(Atomic::nil))
+        }
+        _ => error(String::from("Usage: (disassemble procedure)")),
+    }
+}
+
+/*********************************/
+/******* Below are tests *********/
+/*********************************/
+
+#[cfg(test)]
+mod tests {
+    use crate::lisparse::*;
+
+    fn vm_env() -> Box<Env> {
+        let mut env = Box::new(init_env());
+        env.set_engine(Engine::Vm);
+        env
+    }
+
+    #[test]
+    fn test_vm_fibo() {
+        let mut env = vm_env();
+        Eval("(define fibo (lambda (x) (if (< x 2) x (+ (fibo (- x 1)) (fibo (- x 2))))))", &mut env);
+        assert_eq!(Eval("(fibo 20)", &mut env), LispType::Atom(Atomic::Number(6765)));
+        assert_eq!(Eval("(if (> 1 2) 1)", &mut env), LispType::Atom(Atomic::nil));
+    }
+
+    #[test]
+    fn test_vm_closures_and_tail_calls() {
+        let mut env = vm_env();
+        Eval("(define make-counter (lambda () (define n 0) (lambda () (set! n (+ n 1)) n)))", &mut env);
+        Eval("(define c (make-counter))", &mut env);
+        Eval("(c)", &mut env);
+        assert_eq!(Eval("(c)", &mut env), LispType::Atom(Atomic::Number(2)));
+        Eval("(define count (lambda (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1)))))", &mut env);
+        assert_eq!(Eval("(count 100000 0)", &mut env), LispType::Atom(Atomic::Number(100000)));
+        assert_eq!(Eval("(begin (max 1 2) (min 3 4))", &mut env), LispType::Atom(Atomic::Number(3)));
+    }
+
+    #[test]
+    fn test_engines_share_procedures() {
+        let mut env = Box::new(init_env());
+        Eval("(define add1 (lambda (x) (+ x 1)))", &mut env);
+        env.set_engine(Engine::Vm);
+        Eval("(define twice (lambda (f x) (f (f x))))", &mut env);
+        assert_eq!(Eval("(twice add1 1)", &mut env), LispType::Atom(Atomic::Number(3)));
+        env.set_engine(Engine::Tree);
+        assert_eq!(Eval("(twice add1 5)", &mut env), LispType::Atom(Atomic::Number(7)));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut env = vm_env();
+        Eval("(define fibo (lambda (x) (if (< x 2) x (+ (fibo (- x 1)) (fibo (- x 2))))))", &mut env);
+        let chunk = match Eval("fibo", &mut env) {
+            LispType::Atom(Atomic::Proc(uf)) => super::bytecode(&uf.lambda).to_string(),
+            _ => unreachable!(),
+        };
+        assert!(chunk.starts_with("#<procedure fibo>:"));
+        assert!(chunk.contains("LocalRef(0, 0)"));
+        assert!(chunk.contains("TailCall(2)"));
+        assert_eq!(Eval("(with-output-to-string (lambda () (disassemble fibo)))", &mut env), LispType::Atom(Atomic::Str(chunk.into())));
+        assert!(eval_source("(disassemble car)", &mut env).is_err());
+        assert!(eval_source("(disassemble)", &mut env).is_err());
+    }
+}